@@ -5,40 +5,76 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        Multipart, State,
+        DefaultBodyLimit, Multipart, State,
     },
-    http::{HeaderValue, Method, StatusCode},
+    http::{Method, StatusCode},
     response::{IntoResponse, Json},
     routing::{get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    net::UdpSocket,
+    collections::{hash_map::Entry, HashMap},
+    io,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::broadcast;
 use tower_http::cors::{Any, CorsLayer};
 use uuid::Uuid;
 
+use crate::compress::Codec;
+use crate::config::{Config, DEVICE_TTL};
+use crate::identity::LocalIdentity;
+use crate::known_peers::Expected;
+use crate::manifest::EntryKind;
+use crate::discovery::{self, Discovery};
+use crate::network::{Announcement, Target};
+use crate::session::SessionBuilder;
+use crate::transfer::TransferEvent;
+
 // ── Shared state ──────────────────────────────────────────────────────────────
 
 #[derive(Clone)]
 pub struct AppState {
-    /// Live discovered devices: ip → DeviceInfo
+    /// Live discovered devices: device ID → DeviceInfo
     pub devices: Arc<Mutex<HashMap<String, DeviceInfo>>>,
     /// Broadcast channel — sends ProgressEvent to all connected WebSocket clients
     pub tx: broadcast::Sender<ProgressEvent>,
+    /// Ports and transfer settings
+    pub config: Arc<Config>,
+    /// The key this bridge's transfers are signed with
+    pub identity: Arc<LocalIdentity>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 pub struct DeviceInfo {
     pub id:   String,
     pub name: String,
+    /// Connectable host: IPv4, or IPv6 with a `%scope` when link-local (`fe80::1%3`)
     pub ip:   String,
     pub kind: String, // "pc" | "phone" | "laptop" | "tablet"
+    pub os:   String,
+    /// TCP port the device receives on
+    pub port: u16,
+    pub version:     u8,
+    pub ciphers:     Vec<String>,
+    pub compression: Vec<Codec>,
+    pub fingerprint: Option<String>,
+    /// Unix time (seconds) the device last answered discovery
+    pub last_seen: u64,
+}
+
+impl DeviceInfo {
+    fn new(ip: String, announcement: Announcement) -> Self {
+        let Announcement { version, id, name, kind, os, port, ciphers, compression, fingerprint } = announcement;
+        DeviceInfo { id, name, ip, kind, os, port, version, ciphers, compression, fingerprint, last_seen: unix_now() }
+    }
+
+    /// Whether anything but `last_seen` differs
+    fn changed(&self, other: &DeviceInfo) -> bool {
+        DeviceInfo { last_seen: other.last_seen, ..self.clone() } != *other
+    }
 }
 
 #[derive(Clone, Serialize, Debug)]
@@ -46,6 +82,12 @@ pub struct DeviceInfo {
 pub enum ProgressEvent {
     #[serde(rename = "discovered")]
     Discovered { device: DeviceInfo },
+    /// A known device now announces a different name, address, port or capabilities
+    #[serde(rename = "updated")]
+    Updated { device: DeviceInfo },
+    /// A device stopped answering and was dropped from the list
+    #[serde(rename = "lost")]
+    Lost { device: DeviceInfo },
     #[serde(rename = "progress")]
     Progress { transfer_id: String, percent: f32, speed_mbps: f32 },
     #[serde(rename = "done")]
@@ -68,9 +110,28 @@ pub fn make_router(state: AppState) -> Router {
         .route("/api/ws",         get(ws_handler))
         .route("/api/health",     get(health))
         .with_state(state)
+        // Uploads are whole files from the local UI, not bounded form posts
+        .layer(DefaultBodyLimit::disable())
         .layer(cors)
 }
 
+/// Serve the bridge API on localhost:`config.bridge_port` until the process is stopped
+pub async fn serve(config: Config, identity: LocalIdentity) -> io::Result<()> {
+    let (tx, _) = broadcast::channel(256);
+    let port = config.bridge_port;
+    let state = AppState { devices: Arc::new(Mutex::new(HashMap::new())), tx, config: Arc::new(config), identity: Arc::new(identity) };
+
+    let discovery: Arc<dyn Discovery> = discovery::from_config(&state.config)?.into();
+    if let Err(e) = discovery.advertise(&Announcement::local(&state.config, &state.identity)) {
+        eprintln!("[bridge] discovery responder bind failed: {e}");
+    }
+    start_discovery_loop(state.clone(), discovery);
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("🌉 SPL bridge listening on http://localhost:{}", port);
+    axum::serve(listener, make_router(state)).await
+}
+
 // ── Routes ────────────────────────────────────────────────────────────────────
 
 /// GET /api/health
@@ -78,11 +139,11 @@ async fn health() -> &'static str { "OpenRing SPL Bridge v1.0" }
 
 /// GET /api/discover — returns current device list
 async fn get_devices(State(state): State<AppState>) -> Json<Vec<DeviceInfo>> {
-    let map = state.devices.lock().unwrap();
+    let map = state.devices.lock().unwrap_or_else(|e| e.into_inner());
     Json(map.values().cloned().collect())
 }
 
-/// POST /api/send — multipart: field "file" + field "ip" + optional field "port"
+/// POST /api/send — multipart: field "file" + field "ip" + optional fields "port" and "code"
 async fn send_file(
     State(state): State<AppState>,
     mut multipart: Multipart,
@@ -90,7 +151,8 @@ async fn send_file(
     let mut file_bytes: Option<Vec<u8>> = None;
     let mut file_name = String::from("transfer");
     let mut target_ip = String::new();
-    let mut port: u16 = crate::config::SERVER_PORT as u16;
+    let mut port: Option<u16> = None;
+    let mut code: Option<String> = None;
 
     // Parse multipart fields
     while let Some(field) = multipart.next_field().await.unwrap_or(None) {
@@ -111,7 +173,10 @@ async fn send_file(
                     .await
                     .unwrap_or_default()
                     .parse()
-                    .unwrap_or(port);
+                    .ok();
+            }
+            "code" => {
+                code = field.text().await.ok().filter(|c| !c.is_empty());
             }
             _ => {}
         }
@@ -124,40 +189,66 @@ async fn send_file(
     if target_ip.is_empty() {
         return (StatusCode::BAD_REQUEST, "missing ip").into_response();
     }
+    // Without an explicit port, use the one the device announced; a discovered device
+    // also has to answer with the key it announced
+    let (port, expected) = {
+        let map = state.devices.lock().unwrap_or_else(|e| e.into_inner());
+        match map.values().find(|d| d.ip == target_ip) {
+            Some(d) => (port.unwrap_or(d.port), Expected { host: None, id: Some(d.id.clone()), fingerprint: d.fingerprint.clone() }),
+            None => (port.unwrap_or(state.config.server_port), Expected::default()),
+        }
+    };
+    let Some(peer) = Target::parse(&target_ip, port).map(|t| t.to_string()) else {
+        return (StatusCode::BAD_REQUEST, "invalid ip").into_response();
+    };
 
-    // Write to temp file (SPL needs a file path)
-    let tmp_path = std::env::temp_dir().join(&file_name);
-    if let Err(e) = std::fs::write(&tmp_path, &bytes) {
+    // Write to temp file (SPL needs a file path); keep only the name's last component
+    // so an upload can't escape its own per-transfer directory
+    let transfer_id = Uuid::new_v4().to_string();
+    let file_name = std::path::Path::new(&file_name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "transfer".into());
+    let tmp_dir = std::env::temp_dir().join(format!("spl-bridge-{}", transfer_id));
+    let tmp_path = tmp_dir.join(&file_name);
+    if let Err(e) = std::fs::create_dir_all(&tmp_dir).and_then(|_| std::fs::write(&tmp_path, &bytes)) {
+        let _ = std::fs::remove_dir_all(&tmp_dir);
         return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
     }
 
-    let transfer_id = Uuid::new_v4().to_string();
     let tx          = state.tx.clone();
     let tid_clone   = transfer_id.clone();
-    let ip_clone    = target_ip.clone();
     let size        = bytes.len() as u64;
+    let config      = state.config.clone();
+    let identity    = state.identity.clone();
 
-    // Spawn SPL send in background, stream progress via broadcast channel
+    // Run the SPL engine in the background, stream progress via broadcast channel
     tokio::spawn(async move {
-        use crate::sender::send_file_spl;
-
-        let result = send_file_spl(
-            &ip_clone,
-            port,
-            &tmp_path,
-            size,
-            |percent, speed_mbps| {
-                let _ = tx.send(ProgressEvent::Progress {
-                    transfer_id: tid_clone.clone(),
+        let progress_tx = tx.clone();
+        let progress_id = tid_clone.clone();
+        let on_event = move |event: TransferEvent| {
+            if let TransferEvent::Progress { transferred, total, elapsed } = event {
+                let percent = if total > 0 { transferred as f32 / total as f32 * 100.0 } else { 100.0 };
+                let speed_mbps = transferred as f32 / (1024.0 * 1024.0) / elapsed.as_secs_f32().max(0.01);
+                let _ = progress_tx.send(ProgressEvent::Progress {
+                    transfer_id: progress_id.clone(),
                     percent,
                     speed_mbps,
                 });
-            },
-        )
-        .await;
+            }
+        };
+        let mut session = SessionBuilder::from_config(&config).identity((*identity).clone()).expect(expected).on_event(on_event);
+        if let Some(code) = code {
+            session = session.pairing_code(code);
+        }
+        let result = session.sender().send(&[&tmp_path], &peer).await;
 
         match result {
-            Ok(hash) => {
+            Ok(report) => {
+                let hash = match &report.manifest.entries[0].kind {
+                    EntryKind::File { sha256, .. } => sha256.clone(),
+                    _ => String::new(),
+                };
                 let _ = tx.send(ProgressEvent::Done {
                     transfer_id: tid_clone.clone(),
                     hash,
@@ -172,7 +263,7 @@ async fn send_file(
             }
         }
 
-        let _ = std::fs::remove_file(&tmp_path);
+        let _ = std::fs::remove_dir_all(&tmp_dir);
     });
 
     Json(serde_json::json!({ "transfer_id": transfer_id, "ok": true })).into_response()
@@ -189,90 +280,70 @@ async fn ws_handler(
 async fn handle_socket(mut socket: WebSocket, state: AppState) {
     let mut rx = state.tx.subscribe();
     loop {
-        match rx.recv().await {
-            Ok(event) => {
-                let json = serde_json::to_string(&event).unwrap_or_default();
-                if socket.send(Message::Text(json)).await.is_err() {
-                    break;
-                }
-            }
-            Err(_) => break,
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            // A slow client misses some progress ticks rather than being dropped
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let json = serde_json::to_string(&event).unwrap_or_default();
+        if socket.send(Message::Text(json.into())).await.is_err() {
+            break;
         }
     }
 }
 
-// ── Discovery listener (runs forever in background) ───────────────────────────
-
-pub fn start_discovery_listener(state: AppState) {
-    let tx = state.tx.clone();
-    let devices = state.devices.clone();
+// ── Discovery (runs forever in background) ────────────────────────────────────
 
-    std::thread::spawn(move || {
-        let sock = match UdpSocket::bind(format!("0.0.0.0:{}", crate::config::DISCOVERY_PORT)) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("[bridge] discovery listener bind failed: {e}");
-                return;
-            }
-        };
-        sock.set_read_timeout(Some(Duration::from_secs(1))).ok();
-
-        let mut buf = [0u8; 1024];
-        loop {
-            if let Ok((len, addr)) = sock.recv_from(&mut buf) {
-                if &buf[..len] == b"SPL_HERE" {
-                    let ip = addr.ip().to_string();
-                    let mut map = devices.lock().unwrap();
-                    if !map.contains_key(&ip) {
-                        let dev = DeviceInfo {
-                            id:   Uuid::new_v4().to_string(),
-                            name: hostname_for(&ip),
-                            ip:   ip.clone(),
-                            kind: "pc".into(),
-                        };
-                        map.insert(ip, dev.clone());
-                        let _ = tx.send(ProgressEvent::Discovered { device: dev });
-                    }
-                }
-            }
+/// Record that `device` answered: add it, refresh it, or take its new details, telling UI clients
+fn record_device(devices: &Mutex<HashMap<String, DeviceInfo>>, tx: &broadcast::Sender<ProgressEvent>, device: DeviceInfo) {
+    let mut map = devices.lock().unwrap_or_else(|e| e.into_inner());
+    match map.entry(device.id.clone()) {
+        Entry::Vacant(slot) => {
+            slot.insert(device.clone());
+            let _ = tx.send(ProgressEvent::Discovered { device });
         }
-    });
+        Entry::Occupied(mut slot) if slot.get().changed(&device) => {
+            slot.insert(device.clone());
+            let _ = tx.send(ProgressEvent::Updated { device });
+        }
+        Entry::Occupied(mut slot) => slot.get_mut().last_seen = device.last_seen,
+    }
 }
 
-/// Broadcast SPL_DISCOVER periodically
-pub fn start_discovery_broadcaster() {
-    std::thread::spawn(move || {
-        let sock = UdpSocket::bind("0.0.0.0:0").expect("bind");
-        sock.set_broadcast(true).ok();
-        loop {
-            sock.send_to(
-                b"SPL_DISCOVER",
-                format!("255.255.255.255:{}", crate::config::DISCOVERY_PORT),
-            )
-            .ok();
-            std::thread::sleep(Duration::from_secs(3));
+/// Drop devices that haven't answered for `DEVICE_TTL` seconds, telling UI clients
+fn expire_devices(devices: &Mutex<HashMap<String, DeviceInfo>>, tx: &broadcast::Sender<ProgressEvent>) {
+    let cutoff = unix_now().saturating_sub(DEVICE_TTL);
+    let mut map = devices.lock().unwrap_or_else(|e| e.into_inner());
+    map.retain(|_, device| {
+        let alive = device.last_seen >= cutoff;
+        if !alive {
+            let _ = tx.send(ProgressEvent::Lost { device: device.clone() });
         }
+        alive
     });
 }
 
-/// Respond to SPL_DISCOVER with SPL_HERE (so other devices find us)
-pub fn start_discovery_responder() {
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Look for devices every few seconds with the configured discovery backend
+pub fn start_discovery_loop(state: AppState, discovery: Arc<dyn Discovery>) {
     std::thread::spawn(move || {
-        let sock = UdpSocket::bind(format!("0.0.0.0:{}", crate::config::DISCOVERY_PORT)).ok();
-        if sock.is_none() { return; } // already bound by listener
-        let sock = sock.unwrap();
-        let mut buf = [0u8; 1024];
         loop {
-            if let Ok((len, addr)) = sock.recv_from(&mut buf) {
-                if &buf[..len] == b"SPL_DISCOVER" {
-                    sock.send_to(b"SPL_HERE", addr).ok();
+            match discovery.discover(&state.identity.id, Duration::from_secs(3)) {
+                Ok(devices) => {
+                    for device in devices {
+                        record_device(&state.devices, &state.tx, DeviceInfo::new(device.host, device.announcement));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("[bridge] discovery failed: {e}");
+                    std::thread::sleep(Duration::from_secs(3));
                 }
             }
+            expire_devices(&state.devices, &state.tx);
         }
     });
 }
-
-fn hostname_for(ip: &str) -> String {
-    // Optional: reverse DNS lookup. Falls back to IP.
-    ip.to_string()
-}
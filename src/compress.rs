@@ -0,0 +1,87 @@
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// zstd level used for chunks; fast enough to keep up with gigabit links
+const ZSTD_LEVEL: i32 = 3;
+
+/// Extensions of formats that are already compressed and not worth another pass
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "br", "bz2", "gz", "jpeg", "jpg", "lz4", "mkv", "mov", "mp3", "mp4", "png", "rar", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// Per-chunk compression algorithms, in our order of preference
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+/// Codecs this build can decode
+pub const SUPPORTED: &[Codec] = &[Codec::Zstd, Codec::Lz4];
+
+/// What the sender asked for with `--compress`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CompressMode {
+    /// Negotiate a codec and only compress chunks that actually shrink
+    #[default]
+    Auto,
+    /// Never compress
+    Off,
+    /// Require zstd and try it on every chunk
+    Zstd,
+    /// Require lz4 and try it on every chunk
+    Lz4,
+}
+
+impl CompressMode {
+    /// Codecs to offer the receiver, best first
+    pub fn offer(self) -> Vec<Codec> {
+        match self {
+            CompressMode::Auto => SUPPORTED.to_vec(),
+            CompressMode::Off => Vec::new(),
+            CompressMode::Zstd => vec![Codec::Zstd],
+            CompressMode::Lz4 => vec![Codec::Lz4],
+        }
+    }
+
+    /// Whether chunks of the file at `path` should be run through the codec at all
+    pub fn worth_trying(self, path: &Path) -> bool {
+        match self {
+            CompressMode::Off => false,
+            CompressMode::Auto => {
+                let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+                !ext.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str()))
+            }
+            CompressMode::Zstd | CompressMode::Lz4 => true,
+        }
+    }
+}
+
+/// Receiver side of negotiation: the first offered codec we can decode
+pub fn negotiate(offered: &[Codec]) -> Option<Codec> {
+    offered.iter().copied().find(|c| SUPPORTED.contains(c))
+}
+
+/// Compress `data`, or return `None` if the result wouldn't be smaller
+pub fn compress(codec: Codec, data: &[u8]) -> Option<Vec<u8>> {
+    let packed = match codec {
+        Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).ok()?,
+        Codec::Lz4 => lz4_flex::block::compress(data),
+    };
+    (packed.len() < data.len()).then_some(packed)
+}
+
+/// Undo `compress`; `len` is the original chunk length from the frame header
+pub fn decompress(codec: Codec, data: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let out = match codec {
+        Codec::Zstd => zstd::bulk::decompress(data, len)?,
+        Codec::Lz4 => lz4_flex::block::decompress(data, len).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    };
+    if out.len() != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompressed chunk has the wrong length"));
+    }
+    Ok(out)
+}
@@ -1,5 +1,239 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::discovery::DiscoveryBackend;
+use crate::error::{Error, Result};
+use crate::known_peers::KeyChangePolicy;
+
 pub const SERVER_PORT: u16 = 5001;
 pub const DISCOVERY_PORT: u16 = 5000;
+pub const BRIDGE_PORT: u16 = 9001; // local HTTP/WebSocket API for the UI
 pub const DISCOVERY_TIMEOUT: u64 = 5; // seconds
+pub const DEVICE_TTL: u64 = 15; // seconds the bridge keeps listing a device that stopped answering
 pub const MAX_RETRIES: u8 = 3;
+pub const PAIRING_ATTEMPTS: u8 = 3; // wrong pairing codes a receiver takes before giving up
+pub const RETRY_BACKOFF_MS: u64 = 500; // first reconnect delay, doubled each attempt
+pub const IO_TIMEOUT: u64 = 30; // seconds a socket read/write may stall
+pub const DECISION_TIMEOUT: u64 = 300; // seconds a sender waits for the receiver to accept
 pub const CHUNK_SIZE_BASE: usize = 2 * 1024 * 1024; // 2 MB base
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 8 * CHUNK_SIZE_BASE; // largest chunk a receiver accepts
+pub const MAX_CHUNKS: u64 = 1 << 24; // most chunks a receiver tracks in one transfer (a 2 MB bitmap)
+pub const THREADS: usize = 4; // chunks a sender reads, compresses and encrypts at once
+pub const STREAMS: u8 = 4; // TCP connections a transfer is spread over
+pub const MAX_STREAMS: u8 = 16;
+pub const ACK_WINDOW: usize = 8 * CHUNK_SIZE_BASE; // unacknowledged bytes a sender may have in flight
+pub const JOURNAL_INTERVAL: u64 = 8; // chunks between resume-journal checkpoints
+pub const DEVICE_KINDS: &[&str] = &["pc", "laptop", "phone", "tablet"];
+
+/// Settings layered as defaults < config file < `SPL_*` environment variables < CLI flags
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// TCP port receivers listen on and senders connect to
+    pub server_port: u16,
+    /// UDP port for discovery probes and answers
+    pub discovery_port: u16,
+    /// Local HTTP port of the desktop UI bridge
+    pub bridge_port: u16,
+    /// Bytes per chunk when sending
+    pub chunk_size: usize,
+    /// Chunks a sender prepares in parallel
+    pub threads: usize,
+    /// TCP connections a sender spreads chunks over, and the most a receiver grants
+    pub streams: u8,
+    /// Reconnects before a transfer is given up
+    pub max_retries: u8,
+    /// Seconds to wait for discovery answers
+    pub discovery_timeout: u64,
+    /// How devices find each other: `broadcast` or `mdns`
+    pub discovery: DiscoveryBackend,
+    /// Where `receive` saves when no destination is given
+    pub download_dir: PathBuf,
+    /// Where `serve` saves, in one folder per sender
+    pub inbox_dir: PathBuf,
+    /// Addresses a receiver accepts senders from; empty accepts anyone
+    pub trusted_peers: Vec<IpAddr>,
+    /// Name announced to other devices; the host name when unset
+    pub device_name: Option<String>,
+    /// Kind of device announced to other devices: pc, laptop, phone or tablet
+    pub device_kind: String,
+    /// What to do when a known peer's key changes: `refuse` or `warn`
+    pub key_change: KeyChangePolicy,
+    /// Fingerprints of the sender keys a receiver accepts; empty accepts any key
+    pub allowed_keys: Vec<String>,
+    /// Largest transfer a receiver accepts, in bytes; unlimited when unset
+    pub max_transfer_size: Option<u64>,
+    /// File extensions a receiver accepts; empty accepts any file
+    pub allowed_extensions: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_port: SERVER_PORT,
+            discovery_port: DISCOVERY_PORT,
+            bridge_port: BRIDGE_PORT,
+            chunk_size: CHUNK_SIZE_BASE,
+            threads: THREADS,
+            streams: STREAMS,
+            max_retries: MAX_RETRIES,
+            discovery_timeout: DISCOVERY_TIMEOUT,
+            discovery: DiscoveryBackend::default(),
+            download_dir: PathBuf::from("."),
+            inbox_dir: PathBuf::from("inbox"),
+            trusted_peers: Vec::new(),
+            device_name: None,
+            device_kind: "pc".into(),
+            key_change: KeyChangePolicy::default(),
+            allowed_keys: Vec::new(),
+            max_transfer_size: None,
+            allowed_extensions: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, overridden by the config file (if there is one) and then by the environment
+    pub fn load() -> Result<Config> {
+        let mut config = match Config::path() {
+            Some(path) if path.exists() => Config::read(&path)?,
+            _ => Config::default(),
+        };
+        config.apply_env(env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// `$SPL_CONFIG`, else `~/.config/spl/config.toml` (`%APPDATA%\SPL\config.toml` on Windows)
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("SPL_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        if cfg!(windows) {
+            return env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("SPL").join("config.toml"));
+        }
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("spl").join("config.toml"))
+    }
+
+    /// Directory of the config file, where other per-device state is kept too
+    pub fn dir() -> Option<PathBuf> {
+        Config::path()?.parent().map(Path::to_path_buf)
+    }
+
+    /// Parse one config file; missing keys keep their defaults
+    pub fn read(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Write the config as TOML, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).map_err(|e| Error::Config(e.to_string()))
+    }
+
+    /// Override settings from `SPL_SERVER_PORT`, `SPL_CHUNK_SIZE`, … (lists such as `SPL_TRUSTED_PEERS` are comma-separated)
+    pub fn apply_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix("SPL_") else { continue };
+            match name {
+                "SERVER_PORT" => self.server_port = parse(&key, &value)?,
+                "DISCOVERY_PORT" => self.discovery_port = parse(&key, &value)?,
+                "BRIDGE_PORT" => self.bridge_port = parse(&key, &value)?,
+                "CHUNK_SIZE" => self.chunk_size = parse(&key, &value)?,
+                "THREADS" => self.threads = parse(&key, &value)?,
+                "STREAMS" => self.streams = parse(&key, &value)?,
+                "MAX_RETRIES" => self.max_retries = parse(&key, &value)?,
+                "DISCOVERY_TIMEOUT" => self.discovery_timeout = parse(&key, &value)?,
+                "DISCOVERY" => self.discovery = parse(&key, &value)?,
+                "DOWNLOAD_DIR" => self.download_dir = PathBuf::from(value),
+                "INBOX_DIR" => self.inbox_dir = PathBuf::from(value),
+                "DEVICE_NAME" => self.device_name = Some(value),
+                "DEVICE_KIND" => self.device_kind = value,
+                "KEY_CHANGE" => self.key_change = parse(&key, &value)?,
+                "TRUSTED_PEERS" => self.trusted_peers = list(&value).map(|peer| parse(&key, peer)).collect::<Result<_>>()?,
+                "ALLOWED_KEYS" => self.allowed_keys = list(&value).map(str::to_string).collect(),
+                "MAX_TRANSFER_SIZE" => self.max_transfer_size = Some(parse(&key, &value)?),
+                "ALLOWED_EXTENSIONS" => self.allowed_extensions = list(&value).map(str::to_string).collect(),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject settings the engine can't work with
+    pub fn validate(&self) -> Result<()> {
+        if !(MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&self.chunk_size) {
+            return Err(Error::Config(format!(
+                "chunk_size must be between {} and {} bytes, got {}",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE, self.chunk_size
+            )));
+        }
+        if self.threads == 0 {
+            return Err(Error::Config("threads must be at least 1".into()));
+        }
+        if !(1..=MAX_STREAMS).contains(&self.streams) {
+            return Err(Error::Config(format!("streams must be between 1 and {}, got {}", MAX_STREAMS, self.streams)));
+        }
+        if !DEVICE_KINDS.contains(&self.device_kind.as_str()) {
+            return Err(Error::Config(format!("device_kind must be one of {}, got {:?}", DEVICE_KINDS.join(", "), self.device_kind)));
+        }
+        if let Some(key) = self.allowed_keys.iter().find(|key| !key.trim().starts_with("SHA256:")) {
+            return Err(Error::Config(format!("allowed_keys must hold fingerprints like SHA256:3f2a:9c1e:…, got {:?}", key)));
+        }
+        if self.server_port == 0 || self.discovery_port == 0 || self.bridge_port == 0 {
+            return Err(Error::Config("ports must be non-zero".into()));
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| Error::Config(format!("{}={:?}: {}", key, value, e)))
+}
+
+/// The non-empty items of a comma-separated list
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty())
+}
+
+/// Open the config file in `$VISUAL`/`$EDITOR`, creating it with the defaults first
+pub fn edit(path: &Path) -> Result<()> {
+    if !path.exists() {
+        Config::default().save(path)?;
+    }
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad".into() } else { "vi".into() });
+    // Editors are often configured with flags, e.g. `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let status = std::process::Command::new(program).args(words).arg(path).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", editor, status)).into());
+    }
+    Config::read(path)?.validate()
+}
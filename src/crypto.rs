@@ -1,55 +1,152 @@
 use aes_gcm::{Aes256Gcm, Nonce};
-use aes_gcm::aead::{Aead, KeyInit};
-// Change 'Mac' to 'KeyInit as MacKeyInit' to avoid naming conflicts if necessary, 
-// but fully qualifying the call below is cleaner.
-use hmac::{Hmac, Mac}; 
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use hkdf::Hkdf;
 use sha2::Sha256;
-use rand::Rng;
-
-type HmacSha256 = Hmac<Sha256>;
-
-pub fn encrypt_chunk(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
-    let cipher = Aes256Gcm::new_from_slice(key).unwrap();
-    let mut rng = rand::thread_rng();
-    let nonce_bytes: [u8; 12] = rng.gen();
-    let nonce = Nonce::from_slice(&nonce_bytes);
-    
-    // Encrypt using AES-256-GCM
-    let ciphertext = cipher.encrypt(nonce, plaintext).expect("Encryption failure");
-
-    // Fix: Use fully qualified syntax to tell Rust to use the Hmac implementation
-    let mut mac = <HmacSha256 as KeyInit>::new_from_slice(key)
-        .expect("HMAC key error");
-    
-    mac.update(&ciphertext);
-    let tag = mac.finalize().into_bytes();
-
-    // Layout: [Nonce (12)] + [Ciphertext (Varies)] + [HMAC Tag (32)]
-    let mut result = Vec::with_capacity(12 + ciphertext.len() + 32);
-    result.extend_from_slice(&nonce_bytes);
-    result.extend_from_slice(&ciphertext);
-    result.extend_from_slice(&tag);
-    result
+
+use crate::error::{Error, Result};
+
+/// Name of the session cipher, as announced in discovery
+pub const CIPHER: &str = "aes-256-gcm";
+
+/// AES-256-GCM authentication tag appended to every sealed segment
+pub const TAG_LEN: usize = 16;
+
+/// One direction of a session, split into independent STREAM encryptors for
+/// chunk frames and control messages so their counters never collide
+pub struct Channel {
+    pub chunks: Stream,
+    pub control: Stream,
+}
+
+impl Channel {
+    /// Derive both subkeys from a handshake key
+    pub fn new(key: &[u8; 32]) -> Result<Self> {
+        Channel::lane(key, 0)
+    }
+
+    /// Subkeys for data connection `lane` of a session. Chunk nonces are unique across the
+    /// whole session, so lanes share the chunk key; each numbers its control messages
+    /// from 0 and so gets its own control key.
+    pub fn lane(key: &[u8; 32], lane: u8) -> Result<Self> {
+        let control = match lane {
+            0 => Stream::derive(key, b"spl stream control")?,
+            n => Stream::derive(key, format!("spl stream control {}", n).as_bytes())?,
+        };
+        Ok(Channel { chunks: Stream::derive(key, b"spl stream chunks")?, control })
+    }
 }
 
-pub fn decrypt_chunk(key: &[u8], data: &[u8]) -> Result<Vec<u8>, &'static str> {
-    // 12 (nonce) + 32 (tag) = 44 minimum
-    if data.len() < 44 { return Err("Chunk too small"); }
+/// STREAM segmented AEAD (Hoang, Reyhanitabar, Rogaway, Vizár) over AES-256-GCM.
+/// Segment `counter` is sealed under the nonce `0^16 || attempt || counter (u64 BE) || last`,
+/// so nonces are never reused (a resent segment gets a new `attempt`), a segment only
+/// opens at the position it was sealed for, and a stream cut short lacks a segment
+/// that opens with the last flag set.
+pub struct Stream {
+    cipher: Aes256Gcm,
+}
+
+impl Stream {
+    /// Subkey for one purpose, expanded from the session key with HKDF-SHA256
+    pub fn derive(key: &[u8; 32], label: &[u8]) -> Result<Self> {
+        let mut subkey = [0u8; 32];
+        Hkdf::<Sha256>::new(None, key)
+            .expand(label, &mut subkey)
+            .map_err(|_| std::io::Error::other("HKDF output length invalid"))?;
+        Ok(Stream { cipher: Aes256Gcm::new(&subkey.into()) })
+    }
+
+    /// Encrypt segment `counter`, authenticating `aad` (the frame header) alongside it
+    pub fn seal(&self, counter: u64, attempt: u8, last: bool, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        self.cipher
+            .encrypt(&nonce(counter, attempt, last), Payload { msg: plaintext, aad })
+            .map_err(|_| Error::Protocol("Segment too large to encrypt".into()))
+    }
+
+    /// Decrypt segment `counter`; fails if it was sealed at another position,
+    /// with a different last flag, or with different `aad`
+    pub fn open(&self, counter: u64, attempt: u8, last: bool, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(Error::Integrity("Segment too small".into()));
+        }
+        self.cipher
+            .decrypt(&nonce(counter, attempt, last), Payload { msg: ciphertext, aad })
+            .map_err(|_| Error::Integrity(format!("Segment {} failed to authenticate", counter)))
+    }
+}
+
+fn nonce(counter: u64, attempt: u8, last: bool) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[2] = attempt;
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Vectors computed independently with Python's `cryptography` package:
+    // HKDF(SHA256, salt=None, info=label) over the key 00 01 .. 1f, then AESGCM.encrypt
+    const KEY: [u8; 32] = {
+        let mut key = [0u8; 32];
+        let mut i = 0;
+        while i < 32 {
+            key[i] = i as u8;
+            i += 1;
+        }
+        key
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn seals_known_answers() {
+        let channel = Channel::new(&KEY).unwrap();
+        assert_eq!(
+            channel.chunks.seal(0, 0, false, b"hello, stream", b"header").unwrap(),
+            hex("6644e39b9951c76c8b06647be371ae1b213e29445346192d4a320adb28"),
+        );
+        assert_eq!(
+            channel.chunks.seal(7, 0, true, b"final chunk", b"").unwrap(),
+            hex("9bcb5826c4b452efa8692df173180dc13116bd15f9796cd40189a7"),
+        );
+    }
+
+    #[test]
+    fn resends_use_fresh_nonces() {
+        // Nonce 00 00 01 || 00..07 || 00, i.e. the first resend of segment 7
+        let stream = Channel::new(&KEY).unwrap().chunks;
+        assert_eq!(stream.seal(7, 1, false, b"final chunk", b"").unwrap(), hex("a4ae5d1cfb2dc31c7d4e3b8c1c72f55006c4ed09a1af1a705942d8"));
+    }
 
-    let nonce_bytes = &data[..12];
-    let tag_start = data.len() - 32;
-    let ciphertext = &data[12..tag_start];
-    let tag = &data[tag_start..];
+    #[test]
+    fn subkeys_are_independent() {
+        let channel = Channel::new(&KEY).unwrap();
+        let sealed = channel.chunks.seal(0, 0, false, b"hello, stream", b"header").unwrap();
+        assert!(channel.control.open(0, 0, false, &sealed, b"header").is_err());
 
-    // Fix: Use fully qualified syntax here as well
-    let mut mac = <HmacSha256 as KeyInit>::new_from_slice(key)
-        .map_err(|_| "Invalid HMAC key")?;
-    
-    mac.update(ciphertext);
-    mac.verify_slice(tag).map_err(|_| "HMAC verification failed")?;
+        // Control subkey matches HKDF(info = "spl stream control") computed externally
+        let control = Stream { cipher: Aes256Gcm::new_from_slice(&hex("53c5e8bf96e36398ebd14d837ef2fe04cdf4bda86e980542c35b1ef524522f1f")).unwrap() };
+        let sealed = channel.control.seal(3, 0, false, b"{}", b"").unwrap();
+        assert_eq!(control.open(3, 0, false, &sealed, b"").unwrap(), b"{}");
+    }
 
-    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid AES key")?;
-    let nonce = Nonce::from_slice(nonce_bytes);
+    #[test]
+    fn opens_only_at_sealed_position() {
+        let stream = Channel::new(&KEY).unwrap().chunks;
+        let sealed = stream.seal(5, 0, false, b"payload", b"aad").unwrap();
+        assert_eq!(stream.open(5, 0, false, &sealed, b"aad").unwrap(), b"payload");
+        assert!(stream.open(6, 0, false, &sealed, b"aad").is_err(), "reordered");
+        assert!(stream.open(5, 1, false, &sealed, b"aad").is_err(), "replayed as a resend");
+        assert!(stream.open(5, 0, true, &sealed, b"aad").is_err(), "promoted to last");
+        assert!(stream.open(5, 0, false, &sealed, b"other").is_err(), "different header");
 
-    cipher.decrypt(nonce, ciphertext).map_err(|_| "Decryption failed")
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(stream.open(5, 0, false, &tampered, b"aad").is_err(), "flipped bit");
+        assert!(stream.open(5, 0, false, &sealed[..TAG_LEN - 1], b"aad").is_err(), "truncated");
+    }
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::{Deserialize, Serialize};
+
+use crate::compress::Codec;
+use crate::config::Config;
+use crate::network::{self, Announcement, Device};
+
+/// DNS-SD service type SPL devices register under
+pub const MDNS_SERVICE_TYPE: &str = "_spl._tcp.local.";
+
+/// A way of making this device findable and of finding the others
+pub trait Discovery: Send + Sync {
+    /// Answer other devices' searches with `announcement` from now on
+    fn advertise(&self, announcement: &Announcement) -> io::Result<()>;
+    /// The compatible devices other than `own_id` (this one) found within `timeout`, sorted by name
+    fn discover(&self, own_id: &str, timeout: Duration) -> io::Result<Vec<Device>>;
+}
+
+/// Which discovery scheme to use, from the `discovery` setting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryBackend {
+    /// SPL's own probes: IPv4 broadcast and IPv6 multicast on the discovery port
+    #[default]
+    Broadcast,
+    /// DNS-SD over mDNS (`_spl._tcp.local`), for networks that filter broadcasts
+    Mdns,
+}
+
+impl FromStr for DiscoveryBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "broadcast" => Ok(DiscoveryBackend::Broadcast),
+            "mdns" => Ok(DiscoveryBackend::Mdns),
+            _ => Err("expected broadcast or mdns".into()),
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryBackend::Broadcast => write!(f, "broadcast"),
+            DiscoveryBackend::Mdns => write!(f, "mdns"),
+        }
+    }
+}
+
+/// The backend `config.discovery` selects
+pub fn from_config(config: &Config) -> io::Result<Box<dyn Discovery>> {
+    Ok(match config.discovery {
+        DiscoveryBackend::Broadcast => Box::new(Broadcast { port: config.discovery_port }),
+        DiscoveryBackend::Mdns => Box::new(Mdns::new()?),
+    })
+}
+
+/// Probes and answers on a UDP port (see [`network::discover_devices`])
+pub struct Broadcast {
+    pub port: u16,
+}
+
+impl Discovery for Broadcast {
+    fn advertise(&self, announcement: &Announcement) -> io::Result<()> {
+        network::start_discovery_responder(self.port, announcement)
+    }
+
+    fn discover(&self, own_id: &str, timeout: Duration) -> io::Result<Vec<Device>> {
+        network::discover_devices(self.port, own_id, timeout)
+    }
+}
+
+/// DNS-SD service registration and browsing over mDNS. The announcement travels in the
+/// TXT record, and the SRV port is the announced receive port.
+pub struct Mdns {
+    daemon: ServiceDaemon,
+    /// Full names of the services we registered, to withdraw when we stop
+    registered: Mutex<Vec<String>>,
+}
+
+impl Mdns {
+    /// Start the mDNS responder thread
+    pub fn new() -> io::Result<Self> {
+        Ok(Mdns { daemon: ServiceDaemon::new().map_err(io::Error::other)?, registered: Mutex::new(Vec::new()) })
+    }
+}
+
+/// Say goodbye, so other devices drop us now rather than when their cache expires
+impl Drop for Mdns {
+    fn drop(&mut self) {
+        let registered = self.registered.get_mut().unwrap_or_else(|e| e.into_inner());
+        for fullname in registered.drain(..) {
+            if let Ok(status) = self.daemon.unregister(&fullname) {
+                status.recv_timeout(Duration::from_secs(1)).ok();
+            }
+        }
+        self.daemon.shutdown().ok();
+    }
+}
+
+impl Discovery for Mdns {
+    fn advertise(&self, announcement: &Announcement) -> io::Result<()> {
+        let mut properties = vec![
+            ("version", announcement.version.to_string()),
+            ("id", announcement.id.clone()),
+            ("name", announcement.name.clone()),
+            ("kind", announcement.kind.clone()),
+            ("os", announcement.os.clone()),
+            ("ciphers", announcement.ciphers.join(",")),
+            ("compression", announcement.compression.iter().map(|c| codec_name(*c)).collect::<Vec<_>>().join(",")),
+        ];
+        if let Some(fingerprint) = &announcement.fingerprint {
+            properties.push(("fingerprint", fingerprint.clone()));
+        }
+        // The ID keeps instance and host names unique, whatever the device is called
+        let host = format!("spl-{}.local.", announcement.id);
+        let service = ServiceInfo::new(MDNS_SERVICE_TYPE, &announcement.id, &host, (), announcement.port, &properties[..])
+            .map_err(io::Error::other)?
+            .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        self.daemon.register(service).map_err(io::Error::other)?;
+        self.registered.lock().unwrap_or_else(|e| e.into_inner()).push(fullname);
+        Ok(())
+    }
+
+    fn discover(&self, own_id: &str, timeout: Duration) -> io::Result<Vec<Device>> {
+        let events = self.daemon.browse(MDNS_SERVICE_TYPE).map_err(io::Error::other)?;
+        let deadline = Instant::now() + timeout;
+        let mut devices = HashMap::new();
+        while let Ok(event) = events.recv_deadline(deadline) {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if let Some(device) = device_from(&info).filter(|d| d.announcement.id != own_id) {
+                    devices.insert(device.announcement.id.clone(), device);
+                }
+            }
+        }
+        self.daemon.stop_browse(MDNS_SERVICE_TYPE).ok();
+
+        let mut device_list: Vec<_> = devices.into_values().collect();
+        device_list.sort_by(|a, b| (&a.announcement.name, &a.host).cmp(&(&b.announcement.name, &b.host)));
+        Ok(device_list)
+    }
+}
+
+/// Rebuild the announcement from a resolved service; `None` if it's incomplete,
+/// incompatible or has no address we can connect to
+fn device_from(info: &ServiceInfo) -> Option<Device> {
+    let text = |key| info.get_property_val_str(key).map(str::to_string);
+    let list = |key| text(key).map(|v| v.split(',').filter(|s| !s.is_empty()).map(str::to_string).collect::<Vec<_>>());
+    let announcement = Announcement {
+        version: text("version")?.parse().ok()?,
+        id: text("id")?,
+        name: text("name")?,
+        kind: text("kind").unwrap_or_else(|| "pc".into()),
+        os: text("os").unwrap_or_default(),
+        port: info.get_port(),
+        ciphers: list("ciphers")?,
+        compression: list("compression")?.iter().filter_map(|name| codec_from_name(name)).collect(),
+        fingerprint: text("fingerprint"),
+    };
+    if !announcement.is_compatible() {
+        return None;
+    }
+    // mDNS addresses carry no interface, so a link-local IPv6 one can't be dialled
+    let mut addresses: Vec<&IpAddr> = info
+        .get_addresses()
+        .iter()
+        .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local()))
+        .collect();
+    addresses.sort_by_key(|ip| (ip.is_ipv6(), **ip));
+    let host = addresses.first()?.to_string();
+    Some(Device { host, announcement })
+}
+
+fn codec_name(codec: Codec) -> &'static str {
+    match codec {
+        Codec::Zstd => "zstd",
+        Codec::Lz4 => "lz4",
+    }
+}
+
+fn codec_from_name(name: &str) -> Option<Codec> {
+    match name {
+        "zstd" => Some(Codec::Zstd),
+        "lz4" => Some(Codec::Lz4),
+        _ => None,
+    }
+}
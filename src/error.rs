@@ -0,0 +1,69 @@
+use std::fmt;
+use std::io;
+
+/// Why a transfer failed
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing files or the network failed
+    Io(io::Error),
+    /// The peer sent something that doesn't follow the protocol
+    Protocol(String),
+    /// The handshake couldn't authenticate the peer, e.g. a wrong pairing code
+    Authentication(String),
+    /// Data failed its authentication tag or checksum
+    Integrity(String),
+    /// The peer refused the transfer or a setting it needs
+    PeerRejected(String),
+    /// Nobody answered discovery, or the peer's address couldn't be reached
+    PeerNotFound(String),
+    /// A config file or `SPL_*` variable holds an unusable setting
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Process exit status for the CLI, so scripts can tell failures apart.
+    /// 2 is shared with argument errors, which clap reports itself.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::Protocol(_) => 3,
+            Error::Authentication(_) => 4,
+            Error::Integrity(_) => 5,
+            Error::PeerRejected(_) => 6,
+            Error::PeerNotFound(_) => 7,
+            Error::Config(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            Error::Authentication(msg) => write!(f, "{}", msg),
+            Error::Integrity(msg) => write!(f, "Integrity check failed: {}", msg),
+            Error::PeerRejected(msg) => write!(f, "Peer rejected the transfer: {}", msg),
+            Error::PeerNotFound(msg) => write!(f, "{}", msg),
+            Error::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,537 @@
+use std::io;
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::error::{Error, Result};
+use crate::identity::{LocalIdentity, PeerIdentity};
+use crate::known_peers::{Expected, KnownPeers, PeerStatus};
+use crate::protocol::PROTOCOL_VERSION;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Protocol tag sent ahead of the sender's ephemeral public key: `SPL` and the protocol version
+const HELLO_MAGIC: &[u8; 4] = &[b'S', b'P', b'L', b'0' + PROTOCOL_VERSION];
+
+/// Handshake modes announced in the sender's hello
+const MODE_OPEN: u8 = 0;
+const MODE_PAIRED: u8 = 1;
+/// Not a handshake: an extra data connection joining a running session
+const MODE_JOIN: u8 = 2;
+
+/// Status byte the receiver answers the hello with
+const STATUS_OK: u8 = 0;
+const STATUS_MODE_MISMATCH: u8 = 1;
+const STATUS_UNKNOWN_SESSION: u8 = 2;
+
+/// Status byte each side answers the other's identity with
+const IDENTITY_ACCEPTED: u8 = 0;
+const IDENTITY_REFUSED: u8 = 1;
+
+/// Longest device ID accepted in an identity message
+const MAX_DEVICE_ID_LEN: usize = 64;
+
+/// Length of a SPAKE2 message over Ed25519 (side byte + group element)
+const PAKE_MSG_LEN: usize = 33;
+
+/// Per-session keys derived from the ephemeral key exchange
+pub struct SessionKeys {
+    /// Protects everything the sender writes to the receiver
+    pub sender_key: [u8; 32],
+    /// Protects everything the receiver writes back to the sender
+    pub receiver_key: [u8; 32],
+    /// Proves a data connection joining the session belongs to the same sender
+    pub join_key: [u8; 32],
+}
+
+/// Random ID a receiver gives a session, quoted by the data connections that join it
+pub type SessionId = [u8; 16];
+
+/// A fresh, unguessable [`SessionId`]
+pub fn session_id() -> SessionId {
+    OsRng.gen()
+}
+
+/// How a connection to a receiver opens
+pub enum Hello {
+    /// A sender starting a session; finish the handshake with [`respond`]
+    Session(SessionHello),
+    /// An extra data connection for a running session
+    Join(JoinRequest),
+}
+
+/// The sender's opening of a session handshake
+pub struct SessionHello {
+    mode: u8,
+    sender_key: PublicKey,
+}
+
+/// A data connection asking to join session `session` as lane `lane`
+pub struct JoinRequest {
+    pub session: SessionId,
+    pub lane: u8,
+    tag: [u8; 32],
+}
+
+impl JoinRequest {
+    /// Whether the request comes from whoever holds the session's keys
+    pub fn verify(&self, join_key: &[u8; 32]) -> bool {
+        join_mac(join_key, &self.session, self.lane).is_ok_and(|mac| mac.verify_slice(&self.tag).is_ok())
+    }
+
+    /// Tell the sender whether the connection joined the session
+    pub async fn answer<S: AsyncWrite + Unpin>(&self, stream: &mut S, accepted: bool) -> Result<()> {
+        stream.write_all(&[if accepted { STATUS_OK } else { STATUS_UNKNOWN_SESSION }]).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+/// The other side of a completed handshake: who it proved to be, and how that
+/// compares with the known-peers file
+#[derive(Clone, Debug)]
+pub struct VerifiedPeer {
+    pub identity: PeerIdentity,
+    pub status: PeerStatus,
+}
+
+/// Generate a short pairing code for the receiver to display, e.g. `482-913`
+pub fn generate_pairing_code() -> String {
+    let n: u32 = OsRng.gen_range(0..1_000_000);
+    format!("{:03}-{:03}", n / 1000, n % 1000)
+}
+
+/// Run the sender side of the handshake: send our ephemeral key, read the receiver's.
+/// With a pairing code, both sides also run SPAKE2 and confirm they derived the same keys.
+/// Finally both prove their long-term identity; the receiver's has to be the one `expected`
+/// and is checked against `known_peers`.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    pairing_code: Option<&str>,
+    identity: &LocalIdentity,
+    known_peers: Option<&KnownPeers>,
+    expected: &Expected,
+) -> Result<(SessionKeys, VerifiedPeer)> {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ours = PublicKey::from(&secret);
+    let mode = if pairing_code.is_some() { MODE_PAIRED } else { MODE_OPEN };
+
+    stream.write_all(HELLO_MAGIC).await?;
+    stream.write_all(&[mode]).await?;
+    stream.write_all(ours.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).await?;
+    if status[0] != STATUS_OK {
+        let msg = if pairing_code.is_some() {
+            "Receiver is not in pairing mode"
+        } else {
+            "Receiver requires a pairing code (use --pair)"
+        };
+        return Err(Error::PeerRejected(msg.into()));
+    }
+    let theirs = read_public_key(stream).await?;
+
+    let mut transcript = transcript(mode, &ours, &theirs);
+    let shared = agree(secret, &theirs)?;
+
+    let pake_key = match pairing_code {
+        Some(code) => {
+            let (state, msg) = Spake2::<Ed25519Group>::start_a(&pairing_password(code), &sender_id(), &receiver_id());
+            stream.write_all(&msg).await?;
+            stream.flush().await?;
+            let reply = read_pake_message(stream).await?;
+            transcript.update(&msg);
+            transcript.update(reply);
+            Some(state.finish(&reply).map_err(|_| pairing_failed())?)
+        }
+        None => None,
+    };
+
+    let salt: [u8; 32] = transcript.finalize().into();
+    let (keys, confirm) = derive_keys(&shared, pake_key.as_deref(), &salt)?;
+    if let Some(confirm) = confirm {
+        stream.write_all(&confirm.sender_tag()?).await?;
+        stream.flush().await?;
+        let mut tag = [0u8; 32];
+        stream.read_exact(&mut tag).await?;
+        confirm.verify_receiver(&tag)?;
+    }
+
+    write_identity(stream, identity, SENDER_IDENTITY, &salt).await?;
+    if read_status(stream).await? != IDENTITY_ACCEPTED {
+        return Err(Error::PeerRejected("Receiver doesn't trust this device's key".into()));
+    }
+    let peer = read_identity(stream, RECEIVER_IDENTITY, &salt).await?;
+    let peer = check_peer(stream, peer, known_peers, expected).await?;
+    Ok((keys, peer))
+}
+
+/// Open an extra data connection for session `session` as `lane`, on a fresh connection
+/// to the same receiver
+pub async fn join<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, join_key: &[u8; 32], session: &SessionId, lane: u8) -> Result<()> {
+    let tag = join_mac(join_key, session, lane)?.finalize().into_bytes();
+    let mut hello = HELLO_MAGIC.to_vec();
+    hello.push(MODE_JOIN);
+    hello.extend_from_slice(session);
+    hello.push(lane);
+    hello.extend_from_slice(&tag);
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+    if read_status(stream).await? != STATUS_OK {
+        return Err(Error::PeerRejected(format!("Receiver refused data connection {}", lane)));
+    }
+    Ok(())
+}
+
+/// Read how a connection to the receiver opens: a new session, or a data connection joining one
+pub async fn read_hello<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Hello> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic).await?;
+    if &magic != HELLO_MAGIC {
+        if magic[..3] == HELLO_MAGIC[..3] {
+            return Err(Error::Protocol(format!(
+                "Peer speaks SPL protocol version {}, this device speaks version {}",
+                magic[3].wrapping_sub(b'0'),
+                PROTOCOL_VERSION
+            )));
+        }
+        return Err(Error::Protocol("Peer is not speaking the SPL protocol".into()));
+    }
+    let mode = read_status(stream).await?;
+    if mode == MODE_JOIN {
+        let mut session = [0u8; 16];
+        stream.read_exact(&mut session).await?;
+        let lane = read_status(stream).await?;
+        let mut tag = [0u8; 32];
+        stream.read_exact(&mut tag).await?;
+        return Ok(Hello::Join(JoinRequest { session, lane, tag }));
+    }
+    Ok(Hello::Session(SessionHello { mode, sender_key: read_public_key(stream).await? }))
+}
+
+/// Run the receiver side of the handshake after [`read_hello`]: send our ephemeral key.
+/// When `pairing_code` is set, senders that don't prove knowledge of it are rejected.
+/// Finally both prove their long-term identity, which is checked against `known_peers`.
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    hello: SessionHello,
+    pairing_code: Option<&str>,
+    identity: &LocalIdentity,
+    known_peers: Option<&KnownPeers>,
+) -> Result<(SessionKeys, VerifiedPeer)> {
+    let SessionHello { mode, sender_key: theirs } = hello;
+
+    let expected = if pairing_code.is_some() { MODE_PAIRED } else { MODE_OPEN };
+    if mode != expected {
+        stream.write_all(&[STATUS_MODE_MISMATCH]).await?;
+        stream.flush().await?;
+        let msg = if pairing_code.is_some() {
+            "Sender did not supply a pairing code"
+        } else {
+            "Sender requested pairing but receiver is not in pairing mode"
+        };
+        return Err(Error::Authentication(msg.into()));
+    }
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let ours = PublicKey::from(&secret);
+    stream.write_all(&[STATUS_OK]).await?;
+    stream.write_all(ours.as_bytes()).await?;
+    stream.flush().await?;
+
+    let mut transcript = transcript(mode, &theirs, &ours);
+    let shared = agree(secret, &theirs)?;
+
+    let pake_key = match pairing_code {
+        Some(code) => {
+            let msg_a = read_pake_message(stream).await?;
+            let (state, msg) = Spake2::<Ed25519Group>::start_b(&pairing_password(code), &sender_id(), &receiver_id());
+            stream.write_all(&msg).await?;
+            stream.flush().await?;
+            transcript.update(msg_a);
+            transcript.update(&msg);
+            Some(state.finish(&msg_a).map_err(|_| pairing_failed())?)
+        }
+        None => None,
+    };
+
+    let salt: [u8; 32] = transcript.finalize().into();
+    let (keys, confirm) = derive_keys(&shared, pake_key.as_deref(), &salt)?;
+    if let Some(confirm) = confirm {
+        let mut tag = [0u8; 32];
+        stream.read_exact(&mut tag).await?;
+        // Answer even on mismatch so the sender reports the wrong code instead of a dropped connection
+        stream.write_all(&confirm.receiver_tag()?).await?;
+        stream.flush().await?;
+        confirm.verify_sender(&tag)?;
+    }
+
+    let peer = read_identity(stream, SENDER_IDENTITY, &salt).await?;
+    let peer = check_peer(stream, peer, known_peers, &Expected::default()).await?;
+    write_identity(stream, identity, RECEIVER_IDENTITY, &salt).await?;
+    if read_status(stream).await? != IDENTITY_ACCEPTED {
+        return Err(Error::PeerRejected("Sender doesn't trust this device's key".into()));
+    }
+    Ok((keys, peer))
+}
+
+/// Signature labels, so a sender's proof can't be replayed as a receiver's
+const SENDER_IDENTITY: &[u8] = b"spl sender identity";
+const RECEIVER_IDENTITY: &[u8] = b"spl receiver identity";
+
+/// Send our public key, device ID and name, signed together with the handshake transcript
+/// so the proof only counts for this connection
+async fn write_identity<S: AsyncWrite + Unpin>(stream: &mut S, identity: &LocalIdentity, label: &[u8], salt: &[u8; 32]) -> Result<()> {
+    let id = truncate(&identity.id, MAX_DEVICE_ID_LEN);
+    let name = truncate(&identity.name, u8::MAX as usize);
+    let public_key = identity.public_key();
+    let signature = identity.sign(&identity_message(label, salt, &public_key, id, name));
+
+    let mut message = public_key.to_vec();
+    message.push(id.len() as u8);
+    message.extend_from_slice(id.as_bytes());
+    message.push(name.len() as u8);
+    message.extend_from_slice(name.as_bytes());
+    message.extend_from_slice(&signature);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read the peer's identity and check its signature
+async fn read_identity<S: AsyncRead + Unpin>(stream: &mut S, label: &[u8], salt: &[u8; 32]) -> Result<PeerIdentity> {
+    let mut public_key = [0u8; 32];
+    stream.read_exact(&mut public_key).await?;
+    let id = read_short_string(stream).await?;
+    let name = read_short_string(stream).await?;
+    let mut signature = [0u8; 64];
+    stream.read_exact(&mut signature).await?;
+
+    // A leading '@' would read back from the known-peers file as a host
+    if id.is_empty() || id.len() > MAX_DEVICE_ID_LEN || !id.chars().all(|c| c.is_ascii_graphic()) || id.starts_with('@') {
+        return Err(Error::Protocol("Peer sent an invalid device ID".into()));
+    }
+    let peer = PeerIdentity { id, name, public_key };
+    peer.verify(&identity_message(label, salt, &peer.public_key, &peer.id, &peer.name), &signature)?;
+    Ok(peer)
+}
+
+/// Check the peer is who we `expected`, look it up in `known_peers` and tell it whether we accept it
+async fn check_peer<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    peer: PeerIdentity,
+    known_peers: Option<&KnownPeers>,
+    expected: &Expected,
+) -> Result<VerifiedPeer> {
+    let status = expected.check(&peer).and_then(|_| match known_peers {
+        Some(known_peers) => known_peers.check(&peer, expected),
+        None => Ok(PeerStatus::Unchecked),
+    });
+    let answer = if status.is_ok() { IDENTITY_ACCEPTED } else { IDENTITY_REFUSED };
+    stream.write_all(&[answer]).await?;
+    stream.flush().await?;
+    Ok(VerifiedPeer { identity: peer, status: status? })
+}
+
+async fn read_status<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u8> {
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).await?;
+    Ok(status[0])
+}
+
+async fn read_short_string<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await?;
+    let mut bytes = vec![0u8; len[0] as usize];
+    stream.read_exact(&mut bytes).await?;
+    String::from_utf8(bytes).map_err(|_| Error::Protocol("Peer sent an identity that isn't UTF-8".into()))
+}
+
+fn identity_message(label: &[u8], salt: &[u8; 32], public_key: &[u8; 32], id: &str, name: &str) -> Vec<u8> {
+    let mut message = label.to_vec();
+    message.extend_from_slice(salt);
+    message.extend_from_slice(public_key);
+    message.push(id.len() as u8);
+    message.extend_from_slice(id.as_bytes());
+    message.push(name.len() as u8);
+    message.extend_from_slice(name.as_bytes());
+    message
+}
+
+/// At most `max` bytes of `s`, cut at a character boundary
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+async fn read_public_key<S: AsyncRead + Unpin>(stream: &mut S) -> Result<PublicKey> {
+    let mut bytes = [0u8; 32];
+    stream.read_exact(&mut bytes).await?;
+    Ok(PublicKey::from(bytes))
+}
+
+async fn read_pake_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<[u8; PAKE_MSG_LEN]> {
+    let mut msg = [0u8; PAKE_MSG_LEN];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+fn transcript(mode: u8, sender: &PublicKey, receiver: &PublicKey) -> Sha256 {
+    let mut transcript = Sha256::new();
+    transcript.update(HELLO_MAGIC);
+    transcript.update([mode]);
+    transcript.update(sender.as_bytes());
+    transcript.update(receiver.as_bytes());
+    transcript
+}
+
+fn agree(secret: EphemeralSecret, peer: &PublicKey) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(peer);
+    if !shared.was_contributory() {
+        return Err(Error::Protocol("Peer sent a low-order public key".into()));
+    }
+    Ok(*shared.as_bytes())
+}
+
+/// Codes are compared on their digits only, so `482-913` and `482913` pair fine
+fn pairing_password(code: &str) -> Password {
+    let digits: String = code.chars().filter(|c| c.is_ascii_digit()).collect();
+    Password::new(digits.as_bytes())
+}
+
+fn sender_id() -> Identity { Identity::new(b"spl sender") }
+fn receiver_id() -> Identity { Identity::new(b"spl receiver") }
+
+fn pairing_failed() -> Error {
+    Error::Authentication("Pairing failed: wrong code or tampered handshake".into())
+}
+
+/// Key-confirmation MAC keys each side proves possession of when pairing
+struct Confirmation {
+    sender_key: [u8; 32],
+    receiver_key: [u8; 32],
+    transcript: Vec<u8>,
+}
+
+impl Confirmation {
+    fn sender_tag(&self) -> Result<[u8; 32]> { Ok(self.mac(&self.sender_key)?.finalize().into_bytes().into()) }
+    fn receiver_tag(&self) -> Result<[u8; 32]> { Ok(self.mac(&self.receiver_key)?.finalize().into_bytes().into()) }
+
+    fn verify_sender(&self, tag: &[u8]) -> Result<()> {
+        self.mac(&self.sender_key)?.verify_slice(tag).map_err(|_| pairing_failed())
+    }
+
+    fn verify_receiver(&self, tag: &[u8]) -> Result<()> {
+        self.mac(&self.receiver_key)?.verify_slice(tag).map_err(|_| pairing_failed())
+    }
+
+    fn mac(&self, key: &[u8; 32]) -> Result<HmacSha256> {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(key).map_err(|_| io::Error::other("HMAC key length invalid"))?;
+        mac.update(&self.transcript);
+        Ok(mac)
+    }
+}
+
+/// MAC a joining data connection presents for `session` and `lane`
+fn join_mac(join_key: &[u8; 32], session: &SessionId, lane: u8) -> Result<HmacSha256> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(join_key).map_err(|_| io::Error::other("HMAC key length invalid"))?;
+    mac.update(b"spl join");
+    mac.update(session);
+    mac.update(&[lane]);
+    Ok(mac)
+}
+
+/// HKDF-SHA256 over the shared secret (plus the SPAKE2 key when pairing), salted
+/// with the handshake transcript hash so each session's keys are bound to the exact exchange
+fn derive_keys(shared: &[u8; 32], pake_key: Option<&[u8]>, salt: &[u8; 32]) -> Result<(SessionKeys, Option<Confirmation>)> {
+    let mut ikm = shared.to_vec();
+    if let Some(pake_key) = pake_key {
+        ikm.extend_from_slice(pake_key);
+    }
+
+    let hk = Hkdf::<Sha256>::new(Some(salt), &ikm);
+    let expand = |label: &[u8]| -> Result<[u8; 32]> {
+        let mut out = [0u8; 32];
+        hk.expand(label, &mut out).map_err(|_| io::Error::other("HKDF output length invalid"))?;
+        Ok(out)
+    };
+
+    let keys = SessionKeys {
+        sender_key: expand(b"spl sender key")?,
+        receiver_key: expand(b"spl receiver key")?,
+        join_key: expand(b"spl join key")?,
+    };
+    let confirm = match pake_key {
+        Some(_) => Some(Confirmation {
+            sender_key: expand(b"spl sender confirm")?,
+            receiver_key: expand(b"spl receiver confirm")?,
+            transcript: salt.to_vec(),
+        }),
+        None => None,
+    };
+    Ok((keys, confirm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run both sides of the handshake over an in-memory connection
+    async fn handshake(
+        sender_code: Option<&str>,
+        receiver_code: Option<&str>,
+    ) -> (Result<(SessionKeys, VerifiedPeer)>, Result<(SessionKeys, VerifiedPeer)>) {
+        let (sender, receiver) = (LocalIdentity::ephemeral("sender"), LocalIdentity::ephemeral("receiver"));
+        let (mut ours, mut theirs) = tokio::io::duplex(4096);
+        let respond = async {
+            let Hello::Session(hello) = read_hello(&mut theirs).await? else {
+                return Err(Error::Protocol("Expected a session hello".into()));
+            };
+            respond(&mut theirs, hello, receiver_code, &receiver, None).await
+        };
+        let expected = Expected::default();
+        let (initiated, responded) = tokio::join!(initiate(&mut ours, sender_code, &sender, None, &expected), respond);
+        if let (Ok((_, to_receiver)), Ok((_, to_sender))) = (&initiated, &responded) {
+            assert_eq!(to_receiver.identity.public_key, receiver.public_key());
+            assert_eq!(to_sender.identity.public_key, sender.public_key());
+        }
+        (initiated, responded)
+    }
+
+    fn assert_same_keys(a: &SessionKeys, b: &SessionKeys) {
+        assert_eq!(a.sender_key, b.sender_key);
+        assert_eq!(a.receiver_key, b.receiver_key);
+        assert_eq!(a.join_key, b.join_key);
+        assert_ne!(a.sender_key, a.receiver_key);
+    }
+
+    #[tokio::test]
+    async fn both_sides_derive_the_same_keys() {
+        let (initiated, responded) = handshake(None, None).await;
+        assert_same_keys(&initiated.unwrap().0, &responded.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn matching_pairing_codes_derive_the_same_keys() {
+        let (initiated, responded) = handshake(Some("482-913"), Some("482-913")).await;
+        assert_same_keys(&initiated.unwrap().0, &responded.unwrap().0);
+    }
+
+    #[tokio::test]
+    async fn mismatched_pairing_codes_fail_on_both_sides() {
+        let (initiated, responded) = handshake(Some("482-913"), Some("482-914")).await;
+        assert!(matches!(initiated, Err(Error::Authentication(_))), "sender got {:?}", initiated.err());
+        assert!(matches!(responded, Err(Error::Authentication(_))), "receiver got {:?}", responded.err());
+    }
+}
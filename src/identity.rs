@@ -0,0 +1,148 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::utils::to_hex;
+
+/// File in the config directory holding this device's ID
+const DEVICE_ID_FILE: &str = "device_id";
+/// File in the config directory holding this device's private key
+const KEY_FILE: &str = "identity.key";
+
+/// This installation's long-term identity: who it says it is, and the key that proves it
+#[derive(Clone)]
+pub struct LocalIdentity {
+    pub id: String,
+    pub name: String,
+    key: SigningKey,
+}
+
+impl LocalIdentity {
+    /// The identity kept in the config directory, with its key created on first use
+    pub fn load(config: &Config) -> Result<LocalIdentity> {
+        let name = device_name(config);
+        let Some(dir) = Config::dir() else {
+            return Ok(LocalIdentity::ephemeral(name));
+        };
+        let key = load_key(&dir.join(KEY_FILE))?;
+        Ok(LocalIdentity { id: device_id(), name, key })
+    }
+
+    /// A throwaway identity that isn't saved, so peers see a new key every time
+    pub fn ephemeral(name: impl Into<String>) -> LocalIdentity {
+        LocalIdentity { id: Uuid::new_v4().to_string(), name: name.into(), key: SigningKey::generate(&mut OsRng) }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.key.sign(message).to_bytes()
+    }
+}
+
+/// Someone else's identity, as proven in the handshake
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub id: String,
+    pub name: String,
+    pub public_key: [u8; 32],
+}
+
+impl PeerIdentity {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    /// Check `signature` over `message` against this peer's key
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8; 64]) -> Result<()> {
+        let key = VerifyingKey::from_bytes(&self.public_key)
+            .map_err(|_| Error::Authentication(format!("{} sent an invalid identity key", self.name)))?;
+        key.verify(message, &Signature::from_bytes(signature))
+            .map_err(|_| Error::Authentication(format!("{} failed to prove its identity", self.name)))
+    }
+}
+
+/// Short digest of a public key for people to compare, e.g. `SHA256:3f2a:9c1e:…` (8 groups)
+pub fn fingerprint(public_key: &[u8; 32]) -> String {
+    let digest = to_hex(&Sha256::digest(public_key)[..16]);
+    let groups: Vec<&str> = (0..digest.len()).step_by(4).map(|i| &digest[i..i + 4]).collect();
+    format!("SHA256:{}", groups.join(":"))
+}
+
+/// Read the private key at `path`, or generate and save one readable only by us
+fn load_key(path: &Path) -> Result<SigningKey> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            let seed: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| Error::Config(format!("{} is not a valid identity key", path.display())))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = SigningKey::generate(&mut OsRng);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&key.to_bytes())?;
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// This device's stable ID, generated on first use and kept next to the config file.
+/// Falls back to a fresh ID (stable only for this run) when that can't be written.
+pub fn device_id() -> String {
+    let Some(path) = Config::dir().map(|dir| dir.join(DEVICE_ID_FILE)) else {
+        return Uuid::new_v4().to_string();
+    };
+    if let Ok(id) = fs::read_to_string(&path) {
+        if let Ok(id) = Uuid::parse_str(id.trim()) {
+            return id.to_string();
+        }
+    }
+    let id = Uuid::new_v4().to_string();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).ok();
+    }
+    fs::write(&path, &id).ok();
+    id
+}
+
+/// The name other devices see: `device_name` from the config, else the host name
+pub fn device_name(config: &Config) -> String {
+    config.device_name.clone().filter(|name| !name.trim().is_empty()).unwrap_or_else(hostname)
+}
+
+/// The machine's host name, from `hostname` or the environment
+pub fn hostname() -> String {
+    Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .or_else(|| env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "spl-device".into())
+}
@@ -0,0 +1,110 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::identity::PeerIdentity;
+use crate::transfer::TransferReport;
+
+/// File in the inbox recording every completed transfer, one JSON object per line
+const LOG_FILE: &str = "transfers.log";
+/// Longest device name kept in a folder name
+const MAX_FOLDER_NAME: usize = 48;
+/// Hex digits of the key fingerprint in a folder name (64 bits)
+const KEY_PREFIX: usize = 16;
+
+/// Where a long-running receiver saves: one subfolder per sending device, plus a log
+#[derive(Clone, Debug)]
+pub struct Inbox {
+    root: PathBuf,
+}
+
+/// One line of `transfers.log`
+#[derive(Serialize)]
+struct LogRecord<'a> {
+    /// Seconds since the Unix epoch when the transfer completed
+    time: u64,
+    sender: &'a str,
+    device_id: &'a str,
+    fingerprint: String,
+    address: String,
+    entries: usize,
+    bytes: u64,
+    saved_to: Option<&'a Path>,
+    seconds: f64,
+}
+
+impl Inbox {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Inbox { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `peer`'s subfolder, created if needed. It is told apart by the start of the key fingerprint,
+    /// the only part of the peer it proved in the handshake; the name in front is just for display,
+    /// so a renamed device keeps the folder it had, along with any transfer it left to resume.
+    pub fn folder(&self, peer: &PeerIdentity) -> io::Result<PathBuf> {
+        let name: String = peer
+            .name
+            .chars()
+            .map(|c| if c.is_alphanumeric() || " -_.".contains(c) { c } else { '_' })
+            .take(MAX_FOLDER_NAME)
+            .collect();
+        let name = name.trim().trim_start_matches('.');
+        let fingerprint = peer.fingerprint();
+        let key: String = fingerprint.trim_start_matches("SHA256:").chars().filter(char::is_ascii_hexdigit).take(KEY_PREFIX).collect();
+        let suffix = format!("-{}", key);
+        let existing = fs::read_dir(&self.root).into_iter().flatten().flatten().find(|entry| {
+            entry.file_name().to_str().is_some_and(|folder| folder.ends_with(&suffix)) && entry.file_type().is_ok_and(|t| t.is_dir())
+        });
+        if let Some(existing) = existing {
+            return Ok(existing.path());
+        }
+        let folder = self.root.join(format!("{}{}", if name.is_empty() { "device" } else { name }, suffix));
+        fs::create_dir_all(&folder)?;
+        Ok(folder)
+    }
+
+    /// Append a completed transfer to `transfers.log`
+    pub fn log(&self, report: &TransferReport) -> io::Result<()> {
+        let record = LogRecord {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            sender: &report.peer_identity.name,
+            device_id: &report.peer_identity.id,
+            fingerprint: report.peer_identity.fingerprint(),
+            address: report.peer.to_string(),
+            entries: report.manifest.entries.len(),
+            bytes: report.manifest.total_size(),
+            saved_to: report.saved_to.as_deref(),
+            seconds: report.elapsed.as_secs_f64(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        fs::create_dir_all(&self.root)?;
+        // One write per line, so concurrent sessions don't interleave within a record
+        OpenOptions::new().create(true).append(true).open(self.root.join(LOG_FILE))?.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folder_follows_the_key() {
+        let root = std::env::temp_dir().join(format!("spl-inbox-{}", std::process::id()));
+        let inbox = Inbox::new(&root);
+        let peer = |name: &str, key: u8| PeerIdentity { id: "a1".into(), name: name.into(), public_key: [key; 32] };
+
+        let folder = inbox.folder(&peer("build-07", 1)).unwrap();
+        assert!(folder.file_name().unwrap().to_str().unwrap().starts_with("build-07-"));
+        assert_eq!(inbox.folder(&peer("build-07b", 1)).unwrap(), folder);
+        assert_ne!(inbox.folder(&peer("build-07", 2)).unwrap(), folder);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::to_hex;
+
+/// Tag at the start of every journal file
+const JOURNAL_MAGIC: &[u8; 4] = b"SPLJ";
+const JOURNAL_VERSION: u8 = 1;
+
+/// Bitmap of chunk indices
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawChunkSet")]
+pub struct ChunkSet {
+    count: u64,
+    bits: Vec<u8>,
+}
+
+/// A [`ChunkSet`] as it arrives from the peer, before its bitmap is checked
+#[derive(Deserialize)]
+struct RawChunkSet {
+    count: u64,
+    bits: Vec<u8>,
+}
+
+impl TryFrom<RawChunkSet> for ChunkSet {
+    type Error = String;
+
+    fn try_from(raw: RawChunkSet) -> std::result::Result<Self, Self::Error> {
+        let count = raw.count;
+        ChunkSet::from_parts(count, raw.bits).ok_or_else(|| format!("chunk bitmap doesn't match its count of {}", count))
+    }
+}
+
+impl ChunkSet {
+    /// Empty set for a transfer of `count` chunks
+    pub fn new(count: u64) -> Self {
+        ChunkSet { count, bits: vec![0u8; count.div_ceil(8) as usize] }
+    }
+
+    /// `None` unless `bits` holds exactly `count` bits, rounded up to whole bytes, with the padding clear
+    fn from_parts(count: u64, bits: Vec<u8>) -> Option<Self> {
+        if bits.len() as u64 != count.div_ceil(8) {
+            return None;
+        }
+        let padding = count % 8;
+        if padding != 0 && bits.last().is_some_and(|&last| last >> padding != 0) {
+            return None;
+        }
+        Some(ChunkSet { count, bits })
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        index < self.count && self.bits[(index / 8) as usize] & (1 << (index % 8)) != 0
+    }
+
+    /// Add `index`, returning false if it was already present
+    pub fn insert(&mut self, index: u64) -> bool {
+        let had = self.contains(index);
+        if index < self.count {
+            self.bits[(index / 8) as usize] |= 1 << (index % 8);
+        }
+        !had
+    }
+
+    /// Number of chunks in the set
+    pub fn len(&self) -> u64 {
+        self.bits.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|&b| b == 0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.len() == self.count
+    }
+
+    pub fn remove(&mut self, index: u64) {
+        if index < self.count {
+            self.bits[(index / 8) as usize] &= !(1 << (index % 8));
+        }
+    }
+
+    /// Indices not yet in the set, in ascending order
+    pub fn missing(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.count).filter(move |&i| !self.contains(i))
+    }
+}
+
+/// Sidecar file next to a partial download recording which chunks are safely on disk,
+/// so an interrupted transfer can pick up where it stopped
+#[derive(Clone)]
+pub struct Journal {
+    path: PathBuf,
+    manifest_hash: [u8; 32],
+    pub chunks: ChunkSet,
+}
+
+impl Journal {
+    /// Journal location for a single-file download at `target`
+    pub fn path_for(target: &Path) -> PathBuf {
+        let mut name = target.as_os_str().to_owned();
+        name.push(".spl-journal");
+        PathBuf::from(name)
+    }
+
+    /// Journal location for a directory download rooted at `root`. Each manifest has its own,
+    /// so transfers sharing a folder (e.g. one sender's concurrent sessions) don't clobber each other's.
+    pub fn path_in(root: &Path, manifest_hash: &[u8; 32]) -> PathBuf {
+        root.join(format!(".spl-journal-{}", to_hex(&manifest_hash[..8])))
+    }
+
+    /// Load the journal at `path` if it belongs to the same manifest, else start a fresh one
+    pub fn open(path: PathBuf, manifest_hash: [u8; 32], chunk_count: u64) -> Journal {
+        let chunks = fs::read(&path).ok().and_then(|bytes| parse(&bytes, &manifest_hash, chunk_count));
+        Journal { path, manifest_hash, chunks: chunks.unwrap_or_else(|| ChunkSet::new(chunk_count)) }
+    }
+
+    /// Persist the chunk bitmap. Callers must sync the chunks' data to disk first:
+    /// the journal must never claim a chunk whose bytes could still be lost in a crash.
+    pub fn save(&self) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(4 + 1 + 32 + 8 + self.chunks.bits.len());
+        bytes.extend_from_slice(JOURNAL_MAGIC);
+        bytes.push(JOURNAL_VERSION);
+        bytes.extend_from_slice(&self.manifest_hash);
+        bytes.extend_from_slice(&self.chunks.count.to_be_bytes());
+        bytes.extend_from_slice(&self.chunks.bits);
+
+        // Write-then-rename so a crash mid-write leaves the previous journal intact
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, &bytes)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Delete the journal once the download has been verified
+    pub fn remove(self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse(bytes: &[u8], manifest_hash: &[u8; 32], chunk_count: u64) -> Option<ChunkSet> {
+    let header_len = 4 + 1 + 32 + 8;
+    if bytes.len() < header_len || &bytes[..4] != JOURNAL_MAGIC || bytes[4] != JOURNAL_VERSION {
+        return None;
+    }
+    if &bytes[5..37] != manifest_hash {
+        return None;
+    }
+    let count = u64::from_be_bytes(bytes[37..45].try_into().ok()?);
+    if count != chunk_count {
+        return None;
+    }
+    ChunkSet::from_parts(count, bytes[header_len..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_bitmaps_are_rejected() {
+        let mut set = ChunkSet::new(10);
+        set.insert(9);
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(serde_json::from_str::<ChunkSet>(&json).unwrap(), set);
+        for bad in [r#"{"count":10,"bits":[0]}"#, r#"{"count":10,"bits":[0,4]}"#, r#"{"count":10,"bits":[0,0,0]}"#] {
+            assert!(serde_json::from_str::<ChunkSet>(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::identity::{fingerprint, PeerIdentity};
+use crate::utils::{from_hex, to_hex};
+
+/// File in the config directory listing the peers we've exchanged keys with
+const KNOWN_PEERS_FILE: &str = "known_peers";
+/// Marks a line pinning the key that answered at a host and port, rather than a device
+const HOST_PREFIX: char = '@';
+
+/// Held while the file is read, changed and written back, so concurrent handshakes
+/// (e.g. under `serve`) don't drop each other's new entries
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// What to do when a known device turns up with a different key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyChangePolicy {
+    /// Abort the transfer; remove the peer from `known_peers` to accept its new key
+    #[default]
+    Refuse,
+    /// Warn, accept and remember the new key
+    Warn,
+}
+
+impl FromStr for KeyChangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "refuse" => Ok(KeyChangePolicy::Refuse),
+            "warn" => Ok(KeyChangePolicy::Warn),
+            _ => Err("expected refuse or warn".into()),
+        }
+    }
+}
+
+impl fmt::Display for KeyChangePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyChangePolicy::Refuse => write!(f, "refuse"),
+            KeyChangePolicy::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// How a peer's key compares with what we remembered about it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// Never seen before; its key is remembered from now on (trust on first use)
+    New,
+    /// Same key as last time
+    Known,
+    /// A different key than last time, accepted under [`KeyChangePolicy::Warn`]
+    Changed { previous: String },
+    /// There was no known-peers file to check against
+    Unchecked,
+}
+
+/// Who a sender set out to reach. The key that answers has to be the one remembered for
+/// that host, and the one announced in discovery when the device was picked from there.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Expected {
+    /// Host and port connected to, as `host:port` or `[v6-address]:port`
+    pub host: Option<String>,
+    /// Device ID announced in discovery
+    pub id: Option<String>,
+    /// Key fingerprint announced in discovery
+    pub fingerprint: Option<String>,
+}
+
+impl Expected {
+    /// Fail unless `peer` is the device announced in discovery. A mismatch means someone
+    /// else answered between discovery and the connection, so it is refused whatever the policy.
+    pub fn check(&self, peer: &PeerIdentity) -> Result<()> {
+        let fingerprint = peer.fingerprint();
+        let key_differs = self.fingerprint.as_ref().is_some_and(|announced| !announced.eq_ignore_ascii_case(&fingerprint));
+        let id_differs = self.id.as_ref().is_some_and(|announced| *announced != peer.id);
+        if key_differs || id_differs {
+            return Err(Error::Authentication(format!(
+                "{} answered with key {}, which isn't the one the device announced ({}). Someone may be impersonating it",
+                peer.name,
+                fingerprint,
+                self.fingerprint.as_deref().unwrap_or("no key"),
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Everything in the file
+#[derive(Default)]
+struct Entries {
+    devices: Vec<PeerIdentity>,
+    /// Key that answered at each `host:port` a sender connected to
+    hosts: Vec<(String, [u8; 32])>,
+}
+
+/// The `known_peers` file, like SSH's `known_hosts`: one `<device-id> <public-key-hex> <name>` line
+/// per peer, plus `@<host:port> <public-key-hex>` for each receiver we sent to
+#[derive(Clone, Debug)]
+pub struct KnownPeers {
+    path: PathBuf,
+    policy: KeyChangePolicy,
+}
+
+impl KnownPeers {
+    pub fn new(path: impl Into<PathBuf>, policy: KeyChangePolicy) -> Self {
+        KnownPeers { path: path.into(), policy }
+    }
+
+    /// `known_peers` in the config directory, with the configured key-change policy
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Config::dir().map(|dir| KnownPeers::new(dir.join(KNOWN_PEERS_FILE), config.key_change))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check `peer`'s key against the file. It has changed if the host and port we connected
+    /// to, or the device ID it claims, were answered by a different key before: then it is
+    /// refused, or remembered with a warning status, depending on the policy. Otherwise it
+    /// is known if the key was seen before, whatever the device now calls itself, and new
+    /// if not. Names aren't unique, so they play no part.
+    pub fn check(&self, peer: &PeerIdentity, expected: &Expected) -> Result<PeerStatus> {
+        let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.load()?;
+        let host = expected.host.as_deref();
+
+        let pinned = entries.hosts.iter().find(|(h, _)| host == Some(h.as_str())).map(|(_, key)| *key);
+        let claimed = entries.devices.iter().find(|known| known.id == peer.id).map(|known| known.public_key);
+        let previous = [pinned, claimed].into_iter().flatten().find(|key| *key != peer.public_key);
+        let status = match previous {
+            Some(previous) => {
+                let previous = fingerprint(&previous);
+                if self.policy == KeyChangePolicy::Refuse {
+                    return Err(Error::Authentication(format!(
+                        "The key of {} has changed (was {}, now {}). Someone may be impersonating it. \
+                         If the device was reinstalled, remove its lines from {} and try again",
+                        peer.name,
+                        previous,
+                        peer.fingerprint(),
+                        self.path.display()
+                    )));
+                }
+                entries.devices.retain(|known| known.id != peer.id);
+                PeerStatus::Changed { previous }
+            }
+            None if entries.devices.iter().any(|known| known.public_key == peer.public_key) => PeerStatus::Known,
+            None => PeerStatus::New,
+        };
+        if status != PeerStatus::Known {
+            entries.devices.push(peer.clone());
+        }
+        if let Some(host) = host {
+            entries.hosts.retain(|(h, _)| h != host);
+            entries.hosts.push((host.to_string(), peer.public_key));
+        }
+        self.write(&entries)?;
+        Ok(status)
+    }
+
+    /// Every peer in the file; lines that don't parse are skipped
+    pub fn read(&self) -> Result<Vec<PeerIdentity>> {
+        Ok(self.load()?.devices)
+    }
+
+    fn load(&self) -> Result<Entries> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Entries::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Entries::default();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(first), Some(Some(public_key))) = (fields.next(), fields.next().map(from_hex)) else { continue };
+            match first.strip_prefix(HOST_PREFIX) {
+                Some(host) => entries.hosts.push((host.to_string(), public_key)),
+                None => {
+                    let name = fields.next().unwrap_or("").to_string();
+                    entries.devices.push(PeerIdentity { id: first.to_string(), name, public_key });
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn write(&self, entries: &Entries) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::from("# SPL known peers: <device-id> <public-key> <name>, or @<host:port> <public-key>\n");
+        for peer in &entries.devices {
+            // Names come from the network, so keep each entry on its line
+            let name: String = peer.name.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+            text.push_str(&format!("{} {} {}\n", peer.id, to_hex(&peer.public_key), name));
+        }
+        for (host, public_key) in &entries.hosts {
+            text.push_str(&format!("{}{} {}\n", HOST_PREFIX, host, to_hex(public_key)));
+        }
+        // Write-then-rename so a crash mid-write leaves the previous file intact
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(id: &str, name: &str, key: u8) -> PeerIdentity {
+        PeerIdentity { id: id.into(), name: name.into(), public_key: [key; 32] }
+    }
+
+    fn known_peers(test: &str, policy: KeyChangePolicy) -> KnownPeers {
+        let path = std::env::temp_dir().join(format!("spl-known-peers-{}-{}", test, std::process::id()));
+        let _ = fs::remove_file(&path);
+        KnownPeers::new(path, policy)
+    }
+
+    fn at(host: &str) -> Expected {
+        Expected { host: Some(host.into()), ..Expected::default() }
+    }
+
+    #[test]
+    fn new_then_known() {
+        let known = known_peers("known", KeyChangePolicy::Refuse);
+        assert_eq!(known.check(&peer("a1", "nas", 1), &at("10.0.0.5:5001")).unwrap(), PeerStatus::New);
+        assert_eq!(known.check(&peer("a1", "nas", 1), &at("10.0.0.5:5001")).unwrap(), PeerStatus::Known);
+        // The same key under a new address or name is still the same device
+        assert_eq!(known.check(&peer("a1", "nas-2", 1), &at("nas.local:5001")).unwrap(), PeerStatus::Known);
+        // Names aren't unique, and neither are hosts without their port
+        assert_eq!(known.check(&peer("b2", "NAS", 2), &Expected::default()).unwrap(), PeerStatus::New);
+        assert_eq!(known.check(&peer("c3", "box", 3), &at("10.0.0.5:5002")).unwrap(), PeerStatus::New);
+        assert_eq!(known.read().unwrap().len(), 3);
+        fs::remove_file(known.path()).unwrap();
+    }
+
+    #[test]
+    fn changed_key_is_refused() {
+        let known = known_peers("refuse", KeyChangePolicy::Refuse);
+        known.check(&peer("a1", "nas", 1), &at("10.0.0.5:5001")).unwrap();
+        known.check(&peer("b2", "laptop", 2), &at("10.0.0.6:5001")).unwrap();
+        // Same ID with a new key, a new device or a known one answering at the pinned address
+        let impostors = [
+            (peer("a1", "nas", 9), Expected::default()),
+            (peer("z9", "x", 9), at("10.0.0.5:5001")),
+            (peer("b2", "laptop", 2), at("10.0.0.5:5001")),
+        ];
+        for (impostor, expected) in impostors {
+            assert!(matches!(known.check(&impostor, &expected), Err(Error::Authentication(_))));
+        }
+        assert_eq!(known.check(&peer("a1", "nas", 1), &at("10.0.0.5:5001")).unwrap(), PeerStatus::Known);
+        fs::remove_file(known.path()).unwrap();
+    }
+
+    #[test]
+    fn changed_key_is_remembered_with_a_warning() {
+        let known = known_peers("warn", KeyChangePolicy::Warn);
+        known.check(&peer("a1", "nas", 1), &at("10.0.0.5:5001")).unwrap();
+        let status = known.check(&peer("a1", "nas", 9), &at("10.0.0.5:5001")).unwrap();
+        assert_eq!(status, PeerStatus::Changed { previous: fingerprint(&[1; 32]) });
+        assert_eq!(known.check(&peer("a1", "nas", 9), &at("10.0.0.5:5001")).unwrap(), PeerStatus::Known);
+        assert_eq!(known.read().unwrap(), vec![peer("a1", "nas", 9)]);
+        fs::remove_file(known.path()).unwrap();
+    }
+
+    #[test]
+    fn announced_key_must_answer() {
+        let announced = Expected { host: None, id: Some("a1".into()), fingerprint: Some(fingerprint(&[1; 32])) };
+        assert!(announced.check(&peer("a1", "nas", 1)).is_ok());
+        assert!(matches!(announced.check(&peer("a1", "nas", 9)), Err(Error::Authentication(_))));
+        assert!(matches!(announced.check(&peer("z9", "nas", 1)), Err(Error::Authentication(_))));
+    }
+}
@@ -0,0 +1,50 @@
+//! SPL (Secure Package Loader): encrypted, resumable file transfer between devices.
+//!
+//! Build a [`Sender`] or [`Receiver`] with [`SessionBuilder`]:
+//!
+//! ```no_run
+//! use spl_rust::{SessionBuilder, TransferEvent};
+//!
+//! # async fn run() -> spl_rust::Result<()> {
+//! let receiver = SessionBuilder::new().pairing_code("482-913").receiver();
+//! let report = receiver.receive("./inbox").await?;
+//! println!("saved {} entries", report.manifest.entries.len());
+//!
+//! let sender = SessionBuilder::new()
+//!     .pairing_code("482-913")
+//!     .on_event(|event| if let TransferEvent::Progress { transferred, total, .. } = event {
+//!         println!("{}/{}", transferred, total);
+//!     })
+//!     .sender();
+//! sender.send(&["./build"], "192.168.1.100:5001").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The lower layers (handshake, framing, crypto, discovery) are public too for
+//! callers that need to drive a connection themselves.
+
+pub mod bridge;
+pub mod compress;
+pub mod config;
+pub mod crypto;
+pub mod discovery;
+pub mod error;
+pub mod handshake;
+pub mod identity;
+pub mod inbox;
+pub mod journal;
+pub mod known_peers;
+pub mod manifest;
+pub mod network;
+pub mod policy;
+pub mod protocol;
+pub mod session;
+pub mod transfer;
+pub mod utils;
+
+pub use compress::CompressMode;
+pub use error::{Error, Result};
+pub use manifest::Manifest;
+pub use session::{Receiver, SessionBuilder, Sender};
+pub use transfer::{ReceiveOptions, SendOptions, TransferEvent, TransferReport};
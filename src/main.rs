@@ -1,68 +1,505 @@
-use clap::{Parser, Subcommand};
-use rand::Rng;
-use crate::network::{discover_devices, start_discovery_responder};
-use crate::transfer::{send_file, receive_file};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-mod crypto;
-mod network;
-mod transfer;
-mod utils;
-mod config;
+use clap::{Parser, Subcommand};
+use spl_rust::bridge;
+use spl_rust::config::{self, Config};
+use spl_rust::handshake::generate_pairing_code;
+use spl_rust::discovery::{self, Discovery};
+use spl_rust::identity::LocalIdentity;
+use spl_rust::inbox::Inbox;
+use spl_rust::known_peers::{Expected, KnownPeers, PeerStatus};
+use spl_rust::manifest::EntryKind;
+use spl_rust::network::{self, Announcement, Device, Target};
+use spl_rust::policy::{Decision, Offer};
+use spl_rust::utils::{print_progress, prompt};
+use spl_rust::{CompressMode, Error, SessionBuilder, TransferEvent};
 
 /// SPL: Secure Package Loader
 #[derive(Parser)]
 #[command(author="Yaman", version="1.0", about="Secure file transfer tool")]
 struct Cli {
+    /// Port to send to or listen on (the HTTP port for `bridge`)
+    #[arg(short, long, global = true)]
+    port: Option<u16>,
+    /// Print extra detail, such as the settings in effect
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+    /// Suppress non-essential output
+    #[arg(short, long, global = true)]
+    quiet: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Send a file to discovered devices
+    /// Send files or directories to an address, or to a device found by discovery
     Send {
-        /// Path to the file to send
-        file: String,
+        /// Optional host[:port] (IPv4, IPv6 or hostname), then the files and directories to send;
+        /// directories are sent recursively. Without an address, devices are discovered.
+        #[arg(required = true, value_name = "[HOST[:PORT]] PATH")]
+        paths: Vec<String>,
+        /// Name, ID or address of the discovered device to send to
+        #[arg(long, value_name = "NAME")]
+        to: Option<String>,
+        /// Pair with the receiver using the code it displays (prompted if not given with --code)
+        #[arg(long)]
+        pair: bool,
+        /// Pairing code shown by the receiver
+        #[arg(long, value_name = "CODE")]
+        code: Option<String>,
+        /// Compress chunks before encryption
+        #[arg(long, value_enum, default_value = "auto", value_name = "MODE")]
+        compress: CompressMode,
     },
-    /// Receive a file
+    /// Receive files
     Receive {
-        /// Path to save the incoming file
-        outfile: String,
+        /// Where to save: a file path for a single file, otherwise a directory [default: download_dir]
+        dest: Option<String>,
+        /// Display a pairing code and only accept a sender that enters it
+        #[arg(long)]
+        pair: bool,
+        /// Accept transfers without asking; the allowed_keys, max_transfer_size and
+        /// allowed_extensions settings still apply
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Keep receiving from any number of senders, saving into per-sender folders of an inbox
+    Serve {
+        /// Inbox directory [default: inbox_dir]
+        inbox: Option<String>,
+    },
+    /// Serve the HTTP/WebSocket API for the desktop UI on localhost
+    Bridge,
+    /// Show this device's name, ID and key fingerprint, and the peers it has met
+    Identity,
+    /// Show or edit the configuration file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
     },
 }
 
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the settings in effect, after the config file, environment and flags
+    Show,
+    /// Open the config file in $VISUAL or $EDITOR, creating it with the defaults first
+    Edit,
+}
+
+/// Entries listed when asking whether to accept an offer
+const OFFER_LISTING: usize = 10;
+
+/// How much the CLI prints besides errors and results
+#[derive(Clone, Copy, PartialEq)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
 fn main() {
     let cli = Cli::parse();
+    let verbosity = match (cli.quiet, cli.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+
+    // Editing has to work even when the current file doesn't parse
+    if let Commands::Config { action: ConfigAction::Edit } = cli.command {
+        let Some(path) = Config::path() else {
+            fail("Config", Error::Config("no config directory (set SPL_CONFIG)".into()));
+        };
+        if let Err(e) = config::edit(&path) {
+            fail("Config", e);
+        }
+        println!("✅ Saved {}", path.display());
+        return;
+    }
+
+    let mut config = Config::load().unwrap_or_else(|e| fail("Config", e));
+    // Flags override the config file and environment
+    if let Some(port) = cli.port {
+        match cli.command {
+            Commands::Bridge => config.bridge_port = port,
+            _ => config.server_port = port,
+        }
+    }
+    if verbosity == Verbosity::Verbose {
+        let source = Config::path().filter(|p| p.exists()).map(|p| p.display().to_string());
+        eprintln!(
+            "⚙ Config {}: port {}, chunks of {} KB, {} threads, {} streams, {} retries",
+            source.as_deref().unwrap_or("defaults"),
+            config.server_port,
+            config.chunk_size / 1024,
+            config.threads,
+            config.streams,
+            config.max_retries,
+        );
+    }
 
-    // Always start discovery responder so this device can be discovered
-    start_discovery_responder();
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => fail("Startup", e.into()),
+    };
+    let on_event = move |event| print_event(event, verbosity);
 
     match cli.command {
-        Commands::Send { file } => {
-            // Discover devices on network
-            let devices = discover_devices();
-            if devices.is_empty() {
-                println!("❌ No devices found on network");
+        Commands::Send { mut paths, to, pair, code, compress } => {
+            let identity = load_identity(&config);
+            let discovery = start_discovery(&config, &identity);
+            let (target, expected) = match direct_target(&paths, to.is_some(), config.server_port) {
+                Some(target) => {
+                    paths.remove(0);
+                    (target, Expected::default())
+                }
+                None => {
+                    let device = pick_device(discovery.as_deref(), &config, &identity, to.as_deref(), verbosity).unwrap_or_else(|e| fail("Send", e));
+                    let target = device
+                        .target()
+                        .unwrap_or_else(|| fail("Send", Error::PeerNotFound(format!("Bad device address {}", device.host))));
+                    // Only the device that was picked may answer
+                    let announcement = device.announcement;
+                    (target, Expected { host: None, id: Some(announcement.id), fingerprint: announcement.fingerprint })
+                }
+            };
+            let code = match code {
+                Some(code) => Some(code),
+                None if pair => Some(prompt("🔑 Enter the pairing code shown on the receiver: ")),
+                None => None,
+            };
+
+            if verbosity != Verbosity::Quiet {
+                println!("\n🚀 Sending {} to {}", paths.join(", "), target);
+            }
+            let peer = target.to_string();
+            let mut session = SessionBuilder::from_config(&config)
+                .identity(identity)
+                .compression(compress)
+                .expect(expected)
+                .on_event(on_event);
+            if let Some(code) = code {
+                session = session.pairing_code(code);
+            }
+            match runtime.block_on(session.sender().send(&paths, &peer)) {
+                Ok(report) => println!(
+                    "\n✅ Transfer complete: {:.2} MB to {} in {:.1}s{}",
+                    report.manifest.total_size() as f64 / 1024.0 / 1024.0,
+                    report.peer,
+                    report.elapsed.as_secs_f64(),
+                    retried(report.attempts),
+                ),
+                Err(e) => fail("Send", e),
+            }
+        }
+
+        Commands::Receive { dest, pair, yes } => {
+            let identity = load_identity(&config);
+            let _discovery = start_discovery(&config, &identity);
+            if verbosity != Verbosity::Quiet {
+                println!("🪪 This device: {} ({})", identity.name, identity.fingerprint());
+            }
+            let dest = dest.map(PathBuf::from).unwrap_or_else(|| config.download_dir.clone());
+            let code = pair.then(generate_pairing_code);
+            if let Some(code) = &code {
+                println!("🔑 Pairing code: {}  (enter it on the sending device)", code);
+            }
+            let mut session = SessionBuilder::from_config(&config).identity(identity).on_event(on_event);
+            if let Some(code) = code {
+                session = session.pairing_code(code);
+            }
+            // Without a terminal to ask on, the policy decides alone
+            if !yes && std::io::stdin().is_terminal() {
+                session = session.on_offer(ask_to_accept);
+            }
+            match runtime.block_on(session.receiver().receive(&dest)) {
+                Ok(report) => {
+                    let saved_to = report.saved_to.unwrap_or_default();
+                    match report.manifest.entries.len() {
+                        1 => println!("✅ File saved as {}", saved_to.display()),
+                        n => println!("✅ Saved {} entries to {}", n, saved_to.display()),
+                    }
+                }
+                Err(e) => fail("Receive", e),
+            }
+        }
+
+        Commands::Serve { inbox } => {
+            let identity = load_identity(&config);
+            let _discovery = start_discovery(&config, &identity);
+            let inbox = Inbox::new(inbox.map(PathBuf::from).unwrap_or_else(|| config.inbox_dir.clone()));
+            if verbosity != Verbosity::Quiet {
+                println!("🪪 This device: {} ({})", identity.name, identity.fingerprint());
+            }
+            let log = inbox.clone();
+            let session = SessionBuilder::from_config(&config)
+                .identity(identity)
+                .on_event(move |event| print_serve_event(event, &log, verbosity));
+            if let Err(e) = runtime.block_on(session.receiver().serve(&inbox)) {
+                fail("Serve", e);
+            }
+        }
+
+        Commands::Bridge => {
+            let identity = load_identity(&config);
+            if let Err(e) = runtime.block_on(bridge::serve(config, identity)) {
+                fail("Bridge", e.into());
+            }
+        }
+
+        Commands::Identity => {
+            let identity = load_identity(&config);
+            println!("🪪 {}", identity.name);
+            println!("   Device ID:   {}", identity.id);
+            println!("   Fingerprint: {}", identity.fingerprint());
+            let Some(known_peers) = KnownPeers::from_config(&config) else {
                 return;
+            };
+            let peers = known_peers.read().unwrap_or_else(|e| fail("Identity", e));
+            println!("\n🤝 {} known peer(s) in {}", peers.len(), known_peers.path().display());
+            for peer in peers {
+                println!("   {} ({}) {}", peer.name, peer.id, peer.fingerprint());
             }
+        }
 
-            println!("\n📱 Discovered devices:");
-            for (i, ip) in devices.iter().enumerate() {
-                println!("  {}: {}", i + 1, ip);
+        Commands::Config { action: ConfigAction::Show } => {
+            match Config::path() {
+                Some(path) if path.exists() => println!("# {}", path.display()),
+                Some(path) => println!("# {} (not created yet; showing defaults)", path.display()),
+                None => println!("# no config file location; showing defaults"),
+            }
+            match config.to_toml() {
+                Ok(toml) => print!("{}", toml),
+                Err(e) => fail("Config", e),
             }
+        }
 
-            // For simplicity, select first device
-            let ip = &devices[0];
-            println!("\n🚀 Sending '{}' to {}", file, ip);
+        Commands::Config { action: ConfigAction::Edit } => unreachable!("handled before loading the config"),
+    }
+}
 
-            // Generate random AES key for this transfer
-            let key: [u8; 32] = rand::thread_rng().gen();
-            send_file(&file, ip, &key);
+/// The address in `send <host[:port]> <paths>`: the first argument, when more follow,
+/// it isn't a local file and it parses as an address. `--to` means every argument is a path.
+fn direct_target(args: &[String], discovering: bool, default_port: u16) -> Option<Target> {
+    if discovering || args.len() < 2 || Path::new(&args[0]).symlink_metadata().is_ok() {
+        return None;
+    }
+    Target::parse(&args[0], default_port)
+}
+
+/// This device's identity, with its key created on first use
+fn load_identity(config: &Config) -> LocalIdentity {
+    LocalIdentity::load(config).unwrap_or_else(|e| fail("Identity", e))
+}
+
+/// The configured discovery backend, already advertising this device; `None` (with a warning) if it can't start.
+/// The bridge answers on the same (shared) port with its own announcement.
+fn start_discovery(config: &Config, identity: &LocalIdentity) -> Option<Box<dyn Discovery>> {
+    let discovery = match discovery::from_config(config) {
+        Ok(discovery) => discovery,
+        Err(e) => {
+            eprintln!("⚠ Discovery ({}) disabled: {}", config.discovery, e);
+            return None;
+        }
+    };
+    if let Err(e) = discovery.advertise(&Announcement::local(config, identity)) {
+        eprintln!("⚠ Discovery responder disabled: {}", e);
+    }
+    Some(discovery)
+}
+
+/// Discover devices and choose one: by `--to`, the only one found, or by asking
+fn pick_device(
+    discovery: Option<&dyn Discovery>,
+    config: &Config,
+    identity: &LocalIdentity,
+    to: Option<&str>,
+    verbosity: Verbosity,
+) -> Result<Device, Error> {
+    let Some(discovery) = discovery else {
+        return Err(Error::PeerNotFound("Discovery is unavailable; give the receiver's address".into()));
+    };
+    if verbosity != Verbosity::Quiet {
+        println!("🔍 Discovering devices...");
+    }
+    let devices = discovery.discover(&identity.id, Duration::from_secs(config.discovery_timeout))?;
+    if devices.is_empty() {
+        return Err(Error::PeerNotFound("No devices found on network".into()));
+    }
+    if let Some(name) = to {
+        // Names aren't unique, so an ID or address settles a tie
+        let mut matches: Vec<_> = devices
+            .into_iter()
+            .filter(|d| d.announcement.name.eq_ignore_ascii_case(name) || d.announcement.id == name || d.host == name)
+            .collect();
+        return match matches.len() {
+            0 => Err(Error::PeerNotFound(format!("No discovered device named {}", name))),
+            1 => Ok(matches.remove(0)),
+            n => Err(Error::PeerNotFound(format!("{} devices are named {}; use an address instead", n, name))),
+        };
+    }
+    if let [device] = devices.as_slice() {
+        return Ok(device.clone());
+    }
+
+    println!("\n📱 Discovered devices:");
+    for (i, device) in devices.iter().enumerate() {
+        println!("  {}: {} [{}, {}]", i + 1, device, device.announcement.kind, device.announcement.os);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(Error::PeerNotFound(format!("{} devices found; choose one with --to", devices.len())));
+    }
+    loop {
+        let answer = prompt(&format!("Send to which device? [1-{}]: ", devices.len()));
+        match answer.parse::<usize>() {
+            Ok(n) if (1..=devices.len()).contains(&n) => return Ok(devices[n - 1].clone()),
+            _ if answer.is_empty() => return Err(Error::PeerNotFound("No device chosen".into())),
+            _ => println!("⚠ Enter a number between 1 and {}", devices.len()),
         }
+    }
+}
 
-        Commands::Receive { outfile } => {
-            println!("🖥 Ready to receive a file. Listening on port {}", crate::config::SERVER_PORT);
-            receive_file(&outfile);
+/// Show what a sender is offering and ask whether to accept it
+fn ask_to_accept(offer: &Offer) -> Decision {
+    let manifest = &offer.manifest;
+    println!(
+        "\n📨 {} ({}) wants to send {} entries ({:.2} MB):",
+        offer.peer.name,
+        network::host_of(&offer.addr),
+        manifest.entries.len(),
+        manifest.total_size() as f64 / 1024.0 / 1024.0
+    );
+    match &offer.status {
+        PeerStatus::New => println!("   First transfer from this device; its key is {}", offer.peer.fingerprint()),
+        PeerStatus::Changed { previous } => println!("   ⚠ Its key changed from {} to {}", previous, offer.peer.fingerprint()),
+        PeerStatus::Known | PeerStatus::Unchecked => println!("   Key {}", offer.peer.fingerprint()),
+    }
+    for entry in manifest.entries.iter().take(OFFER_LISTING) {
+        match &entry.kind {
+            EntryKind::File { size, .. } => println!("   {} ({:.2} MB)", entry.path, *size as f64 / 1024.0 / 1024.0),
+            EntryKind::Dir => println!("   {}/", entry.path),
+            EntryKind::Symlink { target } => println!("   {} -> {}", entry.path, target),
         }
     }
+    if manifest.entries.len() > OFFER_LISTING {
+        println!("   … and {} more", manifest.entries.len() - OFFER_LISTING);
+    }
+    match prompt("Accept? [y/N]: ").to_ascii_lowercase().as_str() {
+        "y" | "yes" => Decision::Accept,
+        _ => Decision::Decline("the receiver declined it".into()),
+    }
+}
+
+/// Report `e` and exit with its status code
+fn fail(what: &str, e: Error) -> ! {
+    eprintln!("\n❌ {} failed: {}", what, e);
+    std::process::exit(e.exit_code());
+}
+
+/// Render engine events on the terminal; quiet mode keeps only warnings
+fn print_event(event: TransferEvent, verbosity: Verbosity) {
+    let warning = matches!(
+        event,
+        TransferEvent::ChunkRejected { .. }
+            | TransferEvent::Retrying { .. }
+            | TransferEvent::Refused { .. }
+            | TransferEvent::Dropped { .. }
+            | TransferEvent::Skipped { .. }
+            | TransferEvent::Declined { .. }
+            | TransferEvent::PeerVerified { status: PeerStatus::Changed { .. }, .. }
+    );
+    if verbosity == Verbosity::Quiet && !warning {
+        return;
+    }
+    match event {
+        TransferEvent::Scanning { inputs } => println!("🔎 Scanning and hashing {} input(s)...", inputs),
+        TransferEvent::Listening { addr } => println!("📥 Receiver ready on {}", addr),
+        TransferEvent::Connected { peer } => println!("✅ Connected to {}", peer),
+        TransferEvent::Refused { peer } => eprintln!("⚠ Refused connection from untrusted {}", peer),
+        TransferEvent::Dropped { peer, error } => eprintln!("⚠ Dropped connection from {}: {}", peer, error),
+        TransferEvent::PeerVerified { peer, status } => match status {
+            PeerStatus::New => println!("🔐 First connection to {}; remembering its key {}", peer.name, peer.fingerprint()),
+            PeerStatus::Known => println!("🔐 Verified {} ({})", peer.name, peer.fingerprint()),
+            PeerStatus::Changed { previous } => eprintln!(
+                "⚠ The key of {} has changed (was {}, now {}); accepted because key_change = \"warn\"",
+                peer.name,
+                previous,
+                peer.fingerprint()
+            ),
+            PeerStatus::Unchecked if verbosity == Verbosity::Verbose => {
+                println!("🔓 {} ({}) not checked: no known_peers file", peer.name, peer.fingerprint())
+            }
+            PeerStatus::Unchecked => {}
+        },
+        TransferEvent::Offered => println!("⏳ Waiting for the receiver to accept..."),
+        TransferEvent::Declined { peer, reason } => eprintln!("🚫 Declined a transfer from {}: {}", peer.name, reason),
+        TransferEvent::Manifest { entries, total_bytes, total_chunks, destination } => {
+            let size = total_bytes as f64 / 1024.0 / 1024.0;
+            match destination {
+                Some(root) => println!("📦 Incoming {} entries ({:.2} MB) → {}", entries, size, root.display()),
+                None => println!("📤 Sending {} entries ({:.2} MB)\nTotal chunks: {}", entries, size, total_chunks),
+            }
+        }
+        TransferEvent::Started { total_chunks, resumed_chunks, codec, streams } => {
+            if resumed_chunks > 0 {
+                println!("↪ Resuming: {} of {} chunks already transferred", resumed_chunks, total_chunks);
+            }
+            if streams > 1 {
+                println!("🔀 Spreading chunks over {} connections", streams);
+            }
+            if let Some(codec) = codec {
+                println!("🗜 Compressing with {:?}", codec);
+            }
+        }
+        TransferEvent::Progress { transferred, total, elapsed } => print_progress(transferred, total, elapsed),
+        TransferEvent::ChunkRejected { index, reason } => eprintln!("\n⚠ Chunk {} was rejected ({}) and will be resent", index, reason),
+        TransferEvent::Retrying { attempt, max, delay: Some(delay), error } => {
+            eprintln!("\n⚠ {} — reconnecting in {:.1}s (attempt {}/{})", error, delay.as_secs_f64(), attempt, max)
+        }
+        TransferEvent::Retrying { attempt, max, delay: None, error } => {
+            eprintln!("\n⚠ {} — waiting for the sender to reconnect ({}/{})", error, attempt, max)
+        }
+        TransferEvent::Verifying => println!("\n🔎 Verifying..."),
+        TransferEvent::Skipped { path, reason } => eprintln!("⚠ Skipping {}: {}", path.display(), reason),
+        TransferEvent::Completed { .. } | TransferEvent::Failed { .. } => {}
+    }
+}
+
+/// Render a server's events as one line each, since sessions interleave, and log completed transfers
+fn print_serve_event(event: TransferEvent, inbox: &Inbox, verbosity: Verbosity) {
+    match event {
+        TransferEvent::Listening { addr } if verbosity != Verbosity::Quiet => {
+            println!("📥 Serving {} on {}", inbox.root().display(), addr)
+        }
+        TransferEvent::Completed { report } => {
+            if let Err(e) = inbox.log(&report) {
+                eprintln!("⚠ Couldn't log the transfer from {}: {}", report.peer, e);
+            }
+            println!(
+                "✅ Received {} entries ({:.2} MB) from {} ({}) in {:.1}s → {}",
+                report.manifest.entries.len(),
+                report.manifest.total_size() as f64 / 1024.0 / 1024.0,
+                report.peer_identity.name,
+                report.peer,
+                report.elapsed.as_secs_f64(),
+                report.saved_to.unwrap_or_default().display(),
+            );
+        }
+        TransferEvent::Failed { peer, error } => eprintln!("⚠ Transfer from {} failed: {}", peer, error),
+        TransferEvent::Refused { .. } | TransferEvent::Dropped { .. } | TransferEvent::Declined { .. } | TransferEvent::Skipped { .. } => {
+            print_event(event, verbosity)
+        }
+        TransferEvent::PeerVerified { status: PeerStatus::New | PeerStatus::Changed { .. }, .. } => print_event(event, verbosity),
+        TransferEvent::Connected { .. } if verbosity == Verbosity::Verbose => print_event(event, verbosity),
+        _ => {}
+    }
+}
+
+/// " after N attempts" when a transfer needed reconnects
+fn retried(attempts: u8) -> String {
+    if attempts > 1 { format!(" after {} attempts", attempts) } else { String::new() }
 }
@@ -0,0 +1,400 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{MAX_CHUNKS, MAX_CHUNK_SIZE};
+use crate::error::{Error, Result};
+use crate::journal::ChunkSet;
+use crate::utils::to_hex;
+
+/// Everything the receiver needs to know about a transfer before its chunks arrive
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Size of every chunk except possibly the last one of each file
+    pub chunk_size: u64,
+    /// Files, directories and symlinks in the order they were walked
+    pub entries: Vec<Entry>,
+}
+
+/// One item of the transfer, addressed relative to the receiver's target directory
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Relative path using `/` separators, e.g. `build/bin/tool`
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+    /// Unix permission bits, when the sender's platform has them
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EntryKind {
+    /// Regular file with its size and hex SHA-256
+    File { size: u64, sha256: String },
+    Dir,
+    /// Symbolic link; `target` is stored exactly as read from the link
+    Symlink { target: String },
+}
+
+/// Where chunk `index` of a transfer lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkSpan {
+    /// Position of the file in `Manifest::entries`
+    pub entry: usize,
+    /// Byte offset within that file
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Manifest {
+    /// Walk `paths` (files, directories or symlinks) and describe them, hashing every file.
+    /// Returns the manifest, the local source path of each entry, and anything else found
+    /// (sockets, devices, FIFOs), which is left out.
+    pub fn build(paths: &[PathBuf], chunk_size: u64) -> io::Result<(Manifest, Vec<PathBuf>, Vec<PathBuf>)> {
+        let mut entries = Vec::new();
+        let mut sources = Vec::new();
+        let mut skipped = Vec::new();
+        for path in paths {
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Can't send {:?}: no usable file name", path)))?;
+            if entries.iter().any(|e: &Entry| e.path == name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Two inputs are both named '{}'", name)));
+            }
+            // Follow a symlink named on the command line; links found while walking are sent as links
+            walk(path, name.to_string(), fs::metadata(path)?, &mut entries, &mut sources, &mut skipped)?;
+        }
+        Ok((Manifest { chunk_size, entries }, sources, skipped))
+    }
+
+    /// SHA-256 of the serialized manifest, identifying this exact transfer
+    pub fn digest(&self) -> [u8; 32] {
+        let json = serde_json::to_vec(self).expect("manifest serializes");
+        Sha256::digest(&json).into()
+    }
+
+    /// Total bytes across all files. Saturates rather than overflows; [`validate`](Self::validate)
+    /// rejects manifests where that would happen.
+    pub fn total_size(&self) -> u64 {
+        self.file_sizes().fold(0, u64::saturating_add)
+    }
+
+    fn file_sizes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries.iter().filter_map(|e| match e.kind { EntryKind::File { size, .. } => Some(size), _ => None })
+    }
+
+    /// Chunk index → file mapping for this manifest
+    pub fn layout(&self) -> Layout {
+        let mut files = Vec::new();
+        let mut next = 0;
+        for (entry, e) in self.entries.iter().enumerate() {
+            if let EntryKind::File { size, .. } = e.kind {
+                files.push((next, entry, size));
+                next += size.div_ceil(self.chunk_size);
+            }
+        }
+        Layout { chunk_size: self.chunk_size, files, count: next }
+    }
+
+    /// Reject manifests that would make the receiver write somewhere unexpected, or
+    /// that are too large to track
+    pub fn validate(&self) -> Result<()> {
+        if self.chunk_size == 0 || self.chunk_size > MAX_CHUNK_SIZE as u64 {
+            return Err(invalid(format!("Unsupported chunk size {}", self.chunk_size)));
+        }
+        // The receiver keeps a bit per chunk, so bound the count before anything is allocated
+        let mut total: u64 = 0;
+        let mut chunks: u64 = 0;
+        for size in self.file_sizes() {
+            total = total.checked_add(size).ok_or_else(|| invalid("Transfer size overflows".into()))?;
+            chunks = chunks.saturating_add(size.div_ceil(self.chunk_size));
+            if chunks > MAX_CHUNKS {
+                return Err(invalid(format!("Transfer has more than {} chunks", MAX_CHUNKS)));
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        for entry in &self.entries {
+            relative_path(&entry.path)?;
+            if !seen.insert(entry.path.as_str()) {
+                return Err(invalid(format!("Duplicate entry '{}'", entry.path)));
+            }
+            if let EntryKind::Symlink { target } = &entry.kind {
+                check_symlink_target(&entry.path, target)?;
+            }
+        }
+        // Nothing may be written through a symlink that's part of the same transfer
+        for link in self.entries.iter().filter(|e| matches!(e.kind, EntryKind::Symlink { .. })) {
+            let prefix = format!("{}/", link.path);
+            if let Some(inner) = self.entries.iter().find(|e| e.path.starts_with(&prefix)) {
+                return Err(invalid(format!("Entry '{}' lies inside symlink '{}'", inner.path, link.path)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// Check a finished file against its size and digest
+    pub fn verify(&self, path: &Path) -> Result<()> {
+        let EntryKind::File { size, sha256 } = &self.kind else { return Ok(()) };
+        let actual = fs::metadata(path)?.len();
+        if actual != *size {
+            return Err(Error::Integrity(format!("Size mismatch for '{}': expected {} bytes, got {}", self.path, size, actual)));
+        }
+        let digest = sha256_file(path)?;
+        if digest != *sha256 {
+            return Err(Error::Integrity(format!("SHA-256 mismatch for '{}': expected {}, got {}", self.path, sha256, digest)));
+        }
+        Ok(())
+    }
+
+    /// Restore the sender's permissions and modification time on `path`
+    pub fn apply_metadata(&self, path: &Path) -> io::Result<()> {
+        if matches!(self.kind, EntryKind::Symlink { .. }) {
+            return Ok(());
+        }
+        if let Some(mtime) = self.mtime {
+            let file = if path.is_dir() { File::open(path)? } else { File::options().write(true).open(path)? };
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        set_file_mode(path, self.mode)
+    }
+}
+
+/// Chunk numbering across all files of a manifest: each file's chunks follow the previous file's
+pub struct Layout {
+    chunk_size: u64,
+    /// (first chunk index, entry index, file size) for every file entry
+    files: Vec<(u64, usize, u64)>,
+    count: u64,
+}
+
+impl Layout {
+    pub fn chunk_count(&self) -> u64 {
+        self.count
+    }
+
+    /// Which file chunk `index` belongs to and where in it
+    pub fn span(&self, index: u64) -> ChunkSpan {
+        let pos = self.files.partition_point(|&(first, _, _)| first <= index) - 1;
+        let (first, entry, size) = self.files[pos];
+        let offset = (index - first) * self.chunk_size;
+        ChunkSpan { entry, offset, length: self.chunk_size.min(size - offset) }
+    }
+
+    /// Total bytes covered by the chunks in `set`
+    pub fn bytes_in(&self, set: &ChunkSet) -> u64 {
+        (0..set.count()).filter(|&i| set.contains(i)).map(|i| self.span(i).length).sum()
+    }
+
+    /// Chunk indices belonging to file entry `entry`
+    pub fn chunks_of(&self, entry: usize) -> std::ops::Range<u64> {
+        match self.files.iter().find(|&&(_, e, _)| e == entry) {
+            Some(&(first, _, size)) => first..first + size.div_ceil(self.chunk_size),
+            None => 0..0,
+        }
+    }
+}
+
+/// Turn a manifest path into a relative `PathBuf`, rejecting anything that could
+/// escape the target directory (`..`, absolute paths, drive prefixes, empty parts)
+pub fn relative_path(path: &str) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+    for part in path.split('/') {
+        let ok = !part.is_empty()
+            && part != "."
+            && part != ".."
+            && !part.contains(['\\', ':', '\0'])
+            && matches!(Path::new(part).components().next(), Some(Component::Normal(_)));
+        if !ok {
+            return Err(invalid(format!("Refusing unsafe path '{}'", path)));
+        }
+        out.push(part);
+    }
+    Ok(out)
+}
+
+/// Symlinks must stay inside the transfer: relative, and never climbing above the root
+fn check_symlink_target(link: &str, target: &str) -> Result<()> {
+    if target.is_empty() || target.starts_with('/') || target.contains(['\\', ':', '\0']) {
+        return Err(invalid(format!("Symlink '{}' points outside the transfer ({})", link, target)));
+    }
+    let mut depth = link.split('/').count() as i64 - 1;
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => depth -= 1,
+            _ => depth += 1,
+        }
+        if depth < 0 {
+            return Err(invalid(format!("Symlink '{}' points outside the transfer ({})", link, target)));
+        }
+    }
+    Ok(())
+}
+
+fn walk(
+    path: &Path,
+    rel: String,
+    metadata: fs::Metadata,
+    entries: &mut Vec<Entry>,
+    sources: &mut Vec<PathBuf>,
+    skipped: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    let mode = file_mode(&metadata);
+    let file_type = metadata.file_type();
+
+    let kind = if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Symlink {:?} target is not valid UTF-8", path)))?;
+        EntryKind::Symlink { target: target.replace('\\', "/") }
+    } else if file_type.is_dir() {
+        EntryKind::Dir
+    } else if file_type.is_file() {
+        EntryKind::File { size: metadata.len(), sha256: sha256_file(path)? }
+    } else {
+        skipped.push(path.to_path_buf());
+        return Ok(());
+    };
+
+    let is_dir = matches!(kind, EntryKind::Dir);
+    entries.push(Entry { path: rel.clone(), kind, mode, mtime });
+    sources.push(path.to_path_buf());
+
+    if is_dir {
+        let mut children: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+        children.sort_by_key(|c| c.file_name());
+        for child in children {
+            let name = child.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("File name {:?} is not valid UTF-8", child.path())))?;
+            walk(&child.path(), format!("{}/{}", rel, name), fs::symlink_metadata(child.path())?, entries, sources, skipped)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hex SHA-256 of a file's contents
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(_path: &Path, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+fn invalid(msg: String) -> Error {
+    Error::Protocol(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, size: u64) -> Entry {
+        Entry { path: path.into(), kind: EntryKind::File { size, sha256: String::new() }, mode: None, mtime: None }
+    }
+
+    fn manifest(entries: Vec<Entry>) -> Manifest {
+        Manifest { chunk_size: 1024, entries }
+    }
+
+    fn symlink(path: &str, target: &str) -> Entry {
+        Entry { path: path.into(), kind: EntryKind::Symlink { target: target.into() }, mode: None, mtime: None }
+    }
+
+    #[test]
+    fn plain_relative_paths_are_accepted() {
+        assert_eq!(relative_path("a/b c/d.txt").unwrap(), Path::new("a").join("b c").join("d.txt"));
+        assert!(manifest(vec![file("dir/a", 1), file("dir/b", 1), symlink("dir/link", "../dir/a")]).validate().is_ok());
+    }
+
+    #[test]
+    fn paths_climbing_out_are_rejected() {
+        for path in ["..", "../a", "a/../../b", "a/..", "./a", "a//b", "a/", ""] {
+            assert!(relative_path(path).is_err(), "accepted {:?}", path);
+        }
+    }
+
+    #[test]
+    fn absolute_paths_and_drive_prefixes_are_rejected() {
+        for path in ["/etc/passwd", "C:\\Windows", "C:Windows", "c:/x", "a\\..\\..\\b", "\\\\server\\share", "a\0b"] {
+            assert!(relative_path(path).is_err(), "accepted {:?}", path);
+        }
+    }
+
+    #[test]
+    fn symlinks_escaping_the_transfer_are_rejected() {
+        for (link, target) in [("a", "../b"), ("dir/a", "../../b"), ("a", "/etc/passwd"), ("a", "C:\\x"), ("a", ""), ("dir/a", "x/../../../b")] {
+            assert!(manifest(vec![symlink(link, target)]).validate().is_err(), "accepted {} -> {}", link, target);
+        }
+        assert!(manifest(vec![symlink("dir/a", "../b")]).validate().is_ok());
+    }
+
+    #[test]
+    fn entries_inside_a_symlink_are_rejected() {
+        assert!(manifest(vec![symlink("out", "dir"), file("out/a", 1)]).validate().is_err());
+        assert!(manifest(vec![file("out/a", 1), symlink("out", "dir")]).validate().is_err());
+        // Only a whole component counts
+        assert!(manifest(vec![symlink("out", "dir"), file("outer/a", 1)]).validate().is_ok());
+    }
+
+    #[test]
+    fn duplicate_entries_are_rejected() {
+        assert!(manifest(vec![file("a", 1), file("a", 2)]).validate().is_err());
+    }
+
+    #[test]
+    fn oversized_transfers_are_rejected() {
+        assert!(manifest(vec![file("a", MAX_CHUNKS * 1024)]).validate().is_ok());
+        assert!(manifest(vec![file("a", MAX_CHUNKS * 1024 + 1)]).validate().is_err());
+        let overflowing = manifest(vec![file("a", u64::MAX), file("b", u64::MAX)]);
+        assert!(overflowing.validate().is_err());
+        assert_eq!(overflowing.total_size(), u64::MAX);
+    }
+}
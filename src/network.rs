@@ -1,39 +1,323 @@
-use std::net::{UdpSocket, TcpListener, TcpStream};
-use std::time::Duration;
-use crate::config::{DISCOVERY_PORT, DISCOVERY_TIMEOUT, SERVER_PORT};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
 use std::thread;
 
-pub fn discover_devices() -> Vec<String> {
-    println!("🔍 Discovering devices...");
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    socket.set_read_timeout(Some(Duration::from_secs(DISCOVERY_TIMEOUT))).unwrap();
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
-    socket.send_to(b"SPL_DISCOVER", format!("255.255.255.255:{}", DISCOVERY_PORT)).ok();
-    let mut devices = HashMap::new();
-    let mut buffer = [0u8; 1024];
+use crate::compress::{self, Codec};
+use crate::config::Config;
+use crate::crypto::CIPHER;
+use crate::identity::LocalIdentity;
+use crate::protocol::PROTOCOL_VERSION;
+
+/// Link-local multicast group that IPv6 discovery probes go to (`ff02::5350`, "SP")
+pub const DISCOVERY_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x5350);
+
+/// Tag at the start of a discovery answer, followed by the JSON announcement
+const HERE_MAGIC: &[u8] = b"SPL_HERE";
+
+/// What a device says about itself when it answers discovery
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    /// Wire protocol version; devices only list peers with their own
+    pub version: u8,
+    /// Stable ID that survives address changes
+    pub id: String,
+    pub name: String,
+    /// "pc" | "laptop" | "phone" | "tablet"
+    pub kind: String,
+    /// Operating system, e.g. "linux" or "windows"
+    pub os: String,
+    /// TCP port the device receives transfers on
+    pub port: u16,
+    pub ciphers: Vec<String>,
+    pub compression: Vec<Codec>,
+    /// Fingerprint of the device's identity key
+    pub fingerprint: Option<String>,
+}
 
-    while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
-        if &buffer[..len] == b"SPL_HERE" {
-            devices.insert(addr.ip().to_string(), addr);
+impl Announcement {
+    /// This device, receiving on `config.server_port`
+    pub fn local(config: &Config, identity: &LocalIdentity) -> Announcement {
+        Announcement {
+            version: PROTOCOL_VERSION,
+            id: identity.id.clone(),
+            name: identity.name.clone(),
+            kind: config.device_kind.clone(),
+            os: std::env::consts::OS.into(),
+            port: config.server_port,
+            ciphers: vec![CIPHER.into()],
+            compression: compress::SUPPORTED.to_vec(),
+            fingerprint: Some(identity.fingerprint()),
         }
     }
 
-    let mut device_list: Vec<_> = devices.keys().cloned().collect();
-    device_list.sort();
-    device_list
+    /// `SPL_HERE` followed by the announcement as JSON
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = HERE_MAGIC.to_vec();
+        bytes.extend(serde_json::to_vec(self).expect("announcement serializes"));
+        bytes
+    }
+
+    /// Parse a discovery answer; `None` if it's malformed or from a device we can't talk to
+    pub fn decode(bytes: &[u8]) -> Option<Announcement> {
+        let json = bytes.strip_prefix(HERE_MAGIC)?;
+        let announcement: Announcement = serde_json::from_slice(json).ok()?;
+        announcement.is_compatible().then_some(announcement)
+    }
+
+    /// Same protocol version and a cipher in common
+    pub fn is_compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION && self.ciphers.iter().any(|cipher| cipher == CIPHER)
+    }
+}
+
+/// A device that answered discovery
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Device {
+    /// Address it answered from, with a `%scope` when link-local
+    pub host: String,
+    pub announcement: Announcement,
+}
+
+impl Device {
+    /// Where to send to reach its receiver
+    pub fn target(&self) -> Option<Target> {
+        Target::parse(&self.host, self.announcement.port)
+    }
+}
+
+/// `name (host)`
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.announcement.name, self.host)
+    }
+}
+
+/// Broadcast a discovery probe on `port`, and multicast it on every IPv6 interface,
+/// then collect the other compatible devices that answer within `timeout`, sorted by name
+pub fn discover_devices(port: u16, own_id: &str, timeout: Duration) -> io::Result<Vec<Device>> {
+    let mut targets = vec![SocketAddr::from(([255, 255, 255, 255], port))];
+    targets.extend(ipv6_interfaces().into_iter().map(|index| SocketAddr::V6(SocketAddrV6::new(DISCOVERY_GROUP_V6, port, 0, index))));
+    probe(&targets, own_id, timeout)
+}
+
+/// Send a discovery probe to each of `targets` (broadcast, multicast or unicast addresses)
+/// and collect the compatible devices that answer within `timeout`, sorted by name. Our own
+/// responder answers too, so the device announcing `own_id` is left out.
+pub fn probe(targets: &[SocketAddr], own_id: &str, timeout: Duration) -> io::Result<Vec<Device>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+    // IPv6 is optional: a host without it still discovers over IPv4
+    let socket_v6 = UdpSocket::bind("[::]:0").ok();
+    for target in targets {
+        match (target, &socket_v6) {
+            (SocketAddr::V4(_), _) => socket.send_to(b"SPL_DISCOVER", target).ok(),
+            (SocketAddr::V6(_), Some(socket_v6)) => socket_v6.send_to(b"SPL_DISCOVER", target).ok(),
+            (SocketAddr::V6(_), None) => None,
+        };
+    }
+
+    // A device answering over both IPv4 and IPv6 is listed once, preferring IPv4 so
+    // its address doesn't change from one search to the next
+    let mut devices = HashMap::new();
+    let mut buffer = [0u8; 2048];
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        for socket in std::iter::once(&socket).chain(&socket_v6) {
+            socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+            if let Ok((len, addr)) = socket.recv_from(&mut buffer) {
+                if let Some(announcement) = Announcement::decode(&buffer[..len]).filter(|a| a.id != own_id) {
+                    let device = Device { host: host_of(&addr), announcement };
+                    let known = devices.entry(device.announcement.id.clone()).or_insert_with(|| device.clone());
+                    if known.host.contains(':') && !device.host.contains(':') {
+                        *known = device;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut device_list: Vec<_> = devices.into_values().collect();
+    device_list.sort_by(|a, b| (&a.announcement.name, &a.host).cmp(&(&b.announcement.name, &b.host)));
+    Ok(device_list)
+}
+
+/// Answer discovery probes on `port` in the background with `announcement`, so other
+/// devices find this one, over IPv4 broadcast and the IPv6 discovery group. Answers go
+/// back to whoever asked. The port is shared, so the CLI and the bridge can both answer
+/// on one machine. Fails only if the IPv4 side can't be bound; IPv6 is best effort.
+pub fn start_discovery_responder(port: u16, announcement: &Announcement) -> io::Result<()> {
+    let socket = bind_shared(SocketAddr::from(([0, 0, 0, 0], port)))?;
+    socket.set_broadcast(true)?;
+    answer_probes(socket, announcement);
+    // Hosts without IPv6 are still found over IPv4
+    if let Ok(socket) = bind_group_v6(port) {
+        answer_probes(socket, announcement);
+    }
+    Ok(())
 }
 
-pub fn start_discovery_responder() {
-    thread::spawn(|| {
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", DISCOVERY_PORT)).unwrap();
+/// Reply to every probe `socket` receives with `announcement`, on a background thread
+fn answer_probes(socket: UdpSocket, announcement: &Announcement) {
+    let answer = announcement.encode();
+    thread::spawn(move || {
         let mut buffer = [0u8; 1024];
         loop {
-            if let Ok((len, _)) = socket.recv_from(&mut buffer) {
+            if let Ok((len, addr)) = socket.recv_from(&mut buffer) {
                 if &buffer[..len] == b"SPL_DISCOVER" {
-                    socket.send_to(b"SPL_HERE", format!("255.255.255.255:{}", DISCOVERY_PORT)).ok();
+                    socket.send_to(&answer, addr).ok();
                 }
             }
         }
     });
 }
+
+/// A UDP socket on `addr` that other processes can bind too (SO_REUSEADDR, and SO_REUSEPORT
+/// where it exists). Broadcast and multicast probes reach every one of them.
+fn bind_shared(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    if addr.is_ipv6() {
+        // Leave the IPv4 side of the port to the broadcast responder
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// An IPv6-only UDP socket on `port` that has joined the discovery group on every interface
+/// it could; without any it still answers probes sent straight to this host
+fn bind_group_v6(port: u16) -> io::Result<UdpSocket> {
+    let socket = bind_shared(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))?;
+    for index in ipv6_interfaces() {
+        socket.join_multicast_v6(&DISCOVERY_GROUP_V6, index).ok();
+    }
+    Ok(socket)
+}
+
+/// Indexes of the non-loopback interfaces with an IPv6 address
+fn ipv6_interfaces() -> Vec<u32> {
+    let mut indexes: Vec<u32> = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|iface| iface.ip().is_ipv6() && !iface.is_loopback())
+        .filter_map(|iface| iface.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// Index of the interface called `name` (e.g. `eth0`), for `%eth0` scopes
+pub fn interface_index(name: &str) -> Option<u32> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|iface| iface.name == name)
+        .and_then(|iface| iface.index)
+}
+
+/// A TCP listener on `port` that accepts both IPv6 and IPv4 connections,
+/// or IPv4 only where the host has no IPv6
+pub fn listen_dual_stack(port: u16) -> io::Result<std::net::TcpListener> {
+    let (socket, addr) = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+        Ok(socket) => {
+            socket.set_only_v6(false)?;
+            (socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+        }
+        Err(_) => (Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?, SocketAddr::from(([0, 0, 0, 0], port))),
+    };
+    // Like tokio's own bind, so a restarted receiver doesn't wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// IPv4 peers of a dual-stack socket show up as `::ffff:a.b.c.d`; give them back their IPv4 form
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::from((v4, v6.port())),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// The connectable host part of `addr`: IPv4 unmapped, and link-local IPv6 with its
+/// `%scope`, which is needed to reach it
+pub fn host_of(addr: &SocketAddr) -> String {
+    match canonical(*addr) {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 && v6.ip().is_unicast_link_local() => {
+            format!("{}%{}", v6.ip(), v6.scope_id())
+        }
+        addr => addr.ip().to_string(),
+    }
+}
+
+/// Where to send: a host name or IP address plus a TCP port
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Parse `host`, `host:port`, `1.2.3.4[:port]`, `::1` or `[fe80::1%eth0]:port`;
+    /// `None` if `s` doesn't look like an address at all
+    pub fn parse(s: &str, default_port: u16) -> Option<Target> {
+        let scoped = numeric_scope(s)?;
+        let s = scoped.as_str();
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Some(Target { host: host_of(&addr), port: addr.port() });
+        }
+        let bare = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        let (ip, _) = bare.split_once('%').unwrap_or((bare, ""));
+        if ip.parse::<IpAddr>().is_ok() {
+            return Some(Target { host: bare.to_string(), port: default_port });
+        }
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (s, default_port),
+        };
+        let valid = !host.is_empty()
+            && host.len() <= 253
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+            && !host.starts_with(['-', '.']);
+        valid.then(|| Target { host: host.to_string(), port })
+    }
+}
+
+/// Replace an interface name in a `%scope` with its index, which is what sockets understand;
+/// `None` if there's no such interface
+fn numeric_scope(s: &str) -> Option<String> {
+    let Some((address, rest)) = s.split_once('%') else { return Some(s.to_string()) };
+    let end = rest.find(']').unwrap_or(rest.len());
+    let (scope, tail) = rest.split_at(end);
+    let index = match scope.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => interface_index(scope)?,
+    };
+    Some(format!("{}%{}{}", address, index, tail))
+}
+
+/// `host:port`, with IPv6 hosts in brackets, ready for `TcpStream::connect`
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::identity::PeerIdentity;
+use crate::known_peers::PeerStatus;
+use crate::manifest::{EntryKind, Manifest};
+
+/// A transfer a sender proposes, shown to the receiver before anything is written
+#[derive(Clone, Debug)]
+pub struct Offer {
+    /// Where the sender connected from
+    pub addr: SocketAddr,
+    /// Who the sender proved to be in the handshake
+    pub peer: PeerIdentity,
+    /// How the sender's key compares with the known-peers file
+    pub status: PeerStatus,
+    pub manifest: Manifest,
+}
+
+/// The receiver's answer to an [`Offer`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// Refuse the transfer; the reason is passed on to the sender
+    Decline(String),
+}
+
+/// Callback asked about every offer the [`Policy`] lets through, e.g. to ask the user
+pub type OfferHandler = Arc<dyn Fn(&Offer) -> Decision + Send + Sync>;
+
+/// Rules every offer has to pass, so unattended receivers can decide on their own.
+/// The default accepts anything.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Fingerprints of the only sender keys to accept; empty accepts any key
+    pub allowed_keys: Vec<String>,
+    /// Largest transfer to accept, in bytes
+    pub max_size: Option<u64>,
+    /// File extensions to accept, e.g. `zip` or `tar.gz`; empty accepts any file
+    pub allowed_extensions: Vec<String>,
+}
+
+impl Policy {
+    /// The `allowed_keys`, `max_transfer_size` and `allowed_extensions` settings
+    pub fn from_config(config: &Config) -> Self {
+        Policy {
+            allowed_keys: config.allowed_keys.clone(),
+            max_size: config.max_transfer_size,
+            allowed_extensions: config.allowed_extensions.clone(),
+        }
+    }
+
+    /// Accept `offer`, or decline it with a reason the sender can act on
+    pub fn check(&self, offer: &Offer) -> Decision {
+        let fingerprint = offer.peer.fingerprint();
+        if !self.allowed_keys.is_empty() && !self.allowed_keys.iter().any(|key| key.trim().eq_ignore_ascii_case(&fingerprint)) {
+            return Decision::Decline(format!("this device doesn't accept transfers from key {}", fingerprint));
+        }
+        let size = offer.manifest.total_size();
+        if let Some(limit) = self.max_size.filter(|limit| size > *limit) {
+            return Decision::Decline(format!("{} is over this device's {} limit", human_size(size), human_size(limit)));
+        }
+        if self.allowed_extensions.is_empty() {
+            return Decision::Accept;
+        }
+        let suffixes: Vec<String> = self
+            .allowed_extensions
+            .iter()
+            .map(|ext| format!(".{}", ext.trim().trim_start_matches('.').to_ascii_lowercase()))
+            .collect();
+        let file_names = offer.manifest.entries.iter().filter(|e| matches!(e.kind, EntryKind::File { .. })).map(|e| &e.path);
+        for path in file_names {
+            let name = path.rsplit('/').next().unwrap_or(path).to_ascii_lowercase();
+            if !suffixes.iter().any(|suffix| name.len() > suffix.len() && name.ends_with(suffix.as_str())) {
+                return Decision::Decline(format!("{} isn't one of the accepted file types ({})", path, self.allowed_extensions.join(", ")));
+            }
+        }
+        Decision::Accept
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    if bytes < 1024 * 1024 {
+        return format!("{} bytes", bytes);
+    }
+    format!("{:.2} MB", bytes as f64 / 1024.0 / 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::fingerprint;
+    use crate::manifest::Entry;
+
+    fn offer(files: &[(&str, u64)]) -> Offer {
+        let entries = files
+            .iter()
+            .map(|(path, size)| Entry {
+                path: path.to_string(),
+                kind: EntryKind::File { size: *size, sha256: String::new() },
+                mode: None,
+                mtime: None,
+            })
+            .collect();
+        Offer {
+            addr: SocketAddr::from(([192, 168, 1, 20], 40000)),
+            peer: PeerIdentity { id: "a1".into(), name: "ci".into(), public_key: [7; 32] },
+            status: PeerStatus::Known,
+            manifest: Manifest { chunk_size: 1024, entries },
+        }
+    }
+
+    #[test]
+    fn default_policy_accepts_anything() {
+        assert_eq!(Policy::default().check(&offer(&[("tool.exe", u64::MAX / 2)])), Decision::Accept);
+    }
+
+    #[test]
+    fn only_allowed_keys_are_accepted() {
+        let mut policy = Policy { allowed_keys: vec![fingerprint(&[7; 32]).to_lowercase()], ..Policy::default() };
+        assert_eq!(policy.check(&offer(&[("a.zip", 1)])), Decision::Accept);
+        policy.allowed_keys = vec![fingerprint(&[8; 32])];
+        assert!(matches!(policy.check(&offer(&[("a.zip", 1)])), Decision::Decline(_)));
+    }
+
+    #[test]
+    fn size_and_extensions_are_limited() {
+        let policy = Policy { max_size: Some(100), allowed_extensions: vec![".ZIP".into(), "tar.gz".into()], ..Policy::default() };
+        assert_eq!(policy.check(&offer(&[("out/a.zip", 50), ("b.TAR.GZ", 50)])), Decision::Accept);
+        assert!(matches!(policy.check(&offer(&[("a.zip", 101)])), Decision::Decline(_)));
+        assert!(matches!(policy.check(&offer(&[("a.zip", 1), ("zip/run.sh", 1)])), Decision::Decline(_)));
+        assert!(matches!(policy.check(&offer(&[(".zip", 1)])), Decision::Decline(_)));
+    }
+}
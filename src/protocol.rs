@@ -0,0 +1,177 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::MAX_CHUNK_SIZE;
+use crate::crypto::Stream;
+use crate::compress::Codec;
+use crate::error::{Error, Result};
+use crate::handshake::SessionId;
+use crate::journal::ChunkSet;
+use crate::manifest::Manifest;
+
+/// Version of the whole wire protocol (handshake, messages and frames), announced in
+/// discovery so devices that can't talk to each other don't list each other
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Version of the frame layout below; bumped whenever the header changes
+pub const FRAME_VERSION: u8 = 2;
+
+/// Encoded header size: version, kind, flags, attempt, index, offset, length
+pub const HEADER_LEN: usize = 24;
+
+/// Frame flag: the chunk was compressed with the session's negotiated codec before encryption
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Upper bound on a frame payload so a corrupt length can't make us allocate gigabytes
+const MAX_PAYLOAD_LEN: usize = MAX_CHUNK_SIZE + 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Encrypted slice of the file being transferred
+    Chunk = 1,
+    /// Encrypted JSON control message
+    Control = 2,
+}
+
+/// Control messages exchanged alongside the chunk stream
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Sent by the sender right after the handshake, with the codecs it can compress with
+    /// and how many connections it would like to spread chunks over
+    Manifest { manifest: Manifest, codecs: Vec<Codec>, streams: u8 },
+    /// Receiver's answer to the manifest: chunks it already has from an earlier
+    /// attempt, the codec it picked from the offer (if any), and how many connections
+    /// it grants; extra ones join by quoting `session`
+    Ready { have: ChunkSet, codec: Option<Codec>, streams: u8, session: SessionId },
+    /// Receiver's answer to a manifest it won't accept, instead of `Ready`
+    Declined { reason: String },
+    /// Receiver has authenticated chunk `index` and written it to disk
+    Ack { index: u64 },
+    /// Receiver couldn't use chunk `index` and wants it sent again
+    Nack { index: u64, reason: String },
+}
+
+/// Header preceding every frame on the wire. The encoded bytes double as the
+/// AEAD associated data, so a chunk can't be moved to a different index or offset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub kind: FrameKind,
+    pub flags: u8,
+    /// How many times this chunk was sent before, so a resend is sealed under a fresh nonce
+    pub attempt: u8,
+    /// Chunk number within the transfer
+    pub index: u64,
+    /// Byte offset of the chunk in the file
+    pub offset: u64,
+    /// Plaintext length of the chunk
+    pub length: u32,
+}
+
+impl FrameHeader {
+    pub fn chunk(index: u64, offset: u64, length: u32, flags: u8) -> Self {
+        FrameHeader { kind: FrameKind::Chunk, flags, attempt: 0, index, offset, length }
+    }
+
+    /// Header for the `seq`-th control message of a session
+    pub fn control(seq: u64, length: u32) -> Self {
+        FrameHeader { kind: FrameKind::Control, flags: 0, attempt: 0, index: seq, offset: 0, length }
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0] = FRAME_VERSION;
+        bytes[1] = self.kind as u8;
+        bytes[2] = self.flags;
+        bytes[3] = self.attempt;
+        bytes[4..12].copy_from_slice(&self.index.to_be_bytes());
+        bytes[12..20].copy_from_slice(&self.offset.to_be_bytes());
+        bytes[20..24].copy_from_slice(&self.length.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Self> {
+        if bytes[0] != FRAME_VERSION {
+            return Err(invalid(format!("Unsupported frame version {}", bytes[0])));
+        }
+        let kind = match bytes[1] {
+            1 => FrameKind::Chunk,
+            2 => FrameKind::Control,
+            other => return Err(invalid(format!("Unknown frame kind {}", other))),
+        };
+        Ok(FrameHeader {
+            kind,
+            flags: bytes[2],
+            attempt: bytes[3],
+            index: u64::from_be_bytes(field(bytes, 4)),
+            offset: u64::from_be_bytes(field(bytes, 12)),
+            length: u32::from_be_bytes(field(bytes, 20)),
+        })
+    }
+}
+
+/// The `N` header bytes starting at `at`
+fn field<const N: usize>(bytes: &[u8; HEADER_LEN], at: usize) -> [u8; N] {
+    let mut out = [0u8; N];
+    out.copy_from_slice(&bytes[at..at + N]);
+    out
+}
+
+/// Write `[header][payload len][payload]` as a single buffer, so a frame is
+/// never interleaved with another one written to the same stream
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, header: &FrameHeader, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + 4 + payload.len());
+    frame.extend_from_slice(&header.to_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    Ok(writer.write_all(&frame).await?)
+}
+
+/// Read the next frame, or `None` if the peer closed the stream between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<(FrameHeader, Vec<u8>)>> {
+    let mut header = [0u8; HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let header = FrameHeader::from_bytes(&header)?;
+
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_PAYLOAD_LEN {
+        return Err(invalid(format!("Frame payload of {} bytes exceeds limit", len)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some((header, payload)))
+}
+
+/// Encrypt and send the `seq`-th control message in this direction
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, stream: &Stream, seq: u64, message: &Message) -> Result<()> {
+    let json = serde_json::to_vec(message).map_err(io::Error::other)?;
+    let header = FrameHeader::control(seq, json.len() as u32);
+    let encrypted = stream.seal(seq, 0, false, &json, &header.to_bytes())?;
+    write_frame(writer, &header, &encrypted).await
+}
+
+/// Read the next frame, which must be the `seq`-th control message from the peer, and decrypt it.
+/// A dropped, replayed or reordered message fails to authenticate.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, stream: &Stream, seq: u64) -> Result<Message> {
+    let (header, payload) = read_frame(reader).await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection"))?;
+    if header.kind != FrameKind::Control {
+        return Err(invalid(format!("Expected a control message, got {:?} frame", header.kind)));
+    }
+    let json = stream
+        .open(seq, 0, false, &payload, &header.to_bytes())
+        .map_err(|_| Error::Integrity(format!("Control message {} failed to authenticate", seq)))?;
+    serde_json::from_slice(&json).map_err(|e| invalid(format!("Malformed control message: {}", e)))
+}
+
+fn invalid(msg: String) -> Error {
+    Error::Protocol(msg)
+}
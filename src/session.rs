@@ -0,0 +1,186 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::compress::CompressMode;
+use crate::config::Config;
+use crate::error::Result;
+use crate::identity::LocalIdentity;
+use crate::inbox::Inbox;
+use crate::known_peers::{Expected, KnownPeers};
+use crate::policy::{Decision, Offer, Policy};
+use crate::transfer::{self, ReceiveOptions, SendOptions, TransferEvent, TransferReport};
+
+/// Settings shared by both ends of a transfer; finish with [`sender`](Self::sender)
+/// or [`receiver`](Self::receiver)
+#[derive(Clone, Default)]
+pub struct SessionBuilder {
+    send: SendOptions,
+    receive: ReceiveOptions,
+}
+
+impl SessionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from the port, chunk size, threads, streams, retries, trusted peers, receive policy
+    /// and known-peers file in `config`
+    pub fn from_config(config: &Config) -> Self {
+        let builder = Self::new()
+            .port(config.server_port)
+            .chunk_size(config.chunk_size)
+            .threads(config.threads)
+            .streams(config.streams)
+            .max_retries(config.max_retries)
+            .trusted_peers(config.trusted_peers.clone())
+            .policy(Policy::from_config(config));
+        match KnownPeers::from_config(config) {
+            Some(known_peers) => builder.known_peers(known_peers),
+            None => builder,
+        }
+    }
+
+    /// Pair with a code: the one a receiver displays, or the one it expects
+    pub fn pairing_code(mut self, code: impl Into<String>) -> Self {
+        let code = code.into();
+        self.send.pairing_code = Some(code.clone());
+        self.receive.pairing_code = Some(code);
+        self
+    }
+
+    /// How a sender compresses chunks; receivers decode whatever was negotiated
+    pub fn compression(mut self, mode: CompressMode) -> Self {
+        self.send.compression = mode;
+        self
+    }
+
+    /// Port a receiver listens on; senders take it from the peer address
+    pub fn port(mut self, port: u16) -> Self {
+        self.receive.port = port;
+        self
+    }
+
+    /// Bytes per chunk a sender splits files into
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.send.chunk_size = bytes;
+        self
+    }
+
+    /// Chunks a sender prepares in parallel
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.send.threads = threads;
+        self
+    }
+
+    /// TCP connections a sender spreads chunks over, and the most a receiver grants
+    pub fn streams(mut self, streams: u8) -> Self {
+        self.send.streams = streams;
+        self.receive.streams = streams;
+        self
+    }
+
+    /// Reconnects either side attempts or waits for before giving up
+    pub fn max_retries(mut self, retries: u8) -> Self {
+        self.send.max_retries = retries;
+        self.receive.max_retries = retries;
+        self
+    }
+
+    /// Addresses a receiver accepts senders from; empty accepts anyone
+    pub fn trusted_peers(mut self, peers: Vec<IpAddr>) -> Self {
+        self.receive.trusted_peers = peers;
+        self
+    }
+
+    /// Rules a receiver checks every offer against before accepting it
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.receive.policy = policy;
+        self
+    }
+
+    /// Asked by a receiver about every offer the policy lets through, e.g. to prompt the user.
+    /// Runs on a blocking thread; without it, such offers are accepted.
+    pub fn on_offer(mut self, handler: impl Fn(&Offer) -> Decision + Send + Sync + 'static) -> Self {
+        self.receive.on_offer = Some(Arc::new(handler));
+        self
+    }
+
+    /// Long-term key both sides prove themselves with; a throwaway one by default
+    pub fn identity(mut self, identity: LocalIdentity) -> Self {
+        self.send.identity = identity.clone();
+        self.receive.identity = identity;
+        self
+    }
+
+    /// Check peers' keys against this file, remembering new ones (trust on first use)
+    pub fn known_peers(mut self, known_peers: KnownPeers) -> Self {
+        self.send.known_peers = Some(known_peers.clone());
+        self.receive.known_peers = Some(known_peers);
+        self
+    }
+
+    /// The device a sender has to reach, e.g. the ID and fingerprint it announced in discovery;
+    /// any other key answering is refused
+    pub fn expect(mut self, expected: Expected) -> Self {
+        self.send.expected = expected;
+        self
+    }
+
+    /// Called with every [`TransferEvent`], from the engine's tasks
+    pub fn on_event(mut self, handler: impl Fn(TransferEvent) + Send + Sync + 'static) -> Self {
+        let handler: transfer::EventHandler = Arc::new(handler);
+        self.send.on_event = Some(handler.clone());
+        self.receive.on_event = Some(handler);
+        self
+    }
+
+    pub fn sender(self) -> Sender {
+        Sender::new(self.send)
+    }
+
+    pub fn receiver(self) -> Receiver {
+        Receiver::new(self.receive)
+    }
+}
+
+/// Sends files and directories to a listening receiver
+#[derive(Clone)]
+pub struct Sender {
+    options: SendOptions,
+}
+
+impl Sender {
+    pub fn new(options: SendOptions) -> Self {
+        Sender { options }
+    }
+
+    /// Send `paths` to `peer` (`host:port`), reconnecting and resuming on network errors
+    pub async fn send<P: AsRef<Path>>(&self, paths: &[P], peer: &str) -> Result<TransferReport> {
+        let paths: Vec<PathBuf> = paths.iter().map(|p| p.as_ref().to_path_buf()).collect();
+        transfer::send(&paths, peer, &self.options).await
+    }
+}
+
+/// Waits for senders and saves what they send
+#[derive(Clone)]
+pub struct Receiver {
+    options: ReceiveOptions,
+}
+
+impl Receiver {
+    pub fn new(options: ReceiveOptions) -> Self {
+        Receiver { options }
+    }
+
+    /// Accept a transfer into `dest`: a file path for a single file, otherwise a directory
+    pub async fn receive(&self, dest: impl AsRef<Path>) -> Result<TransferReport> {
+        transfer::receive(dest.as_ref(), &self.options).await
+    }
+
+    /// Keep accepting transfers from any number of senders at once, each into its own
+    /// folder of `inbox`; completed and failed sessions are reported as events
+    pub async fn serve(&self, inbox: &Inbox) -> Result<()> {
+        transfer::serve(inbox, &self.options).await
+    }
+}
@@ -1,152 +1,1187 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
-use std::net::TcpStream;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::collections::HashSet;
 use std::time::{Instant, Duration};
 
-use crate::crypto::{encrypt_chunk, decrypt_chunk};
-use crate::utils::print_progress;
-use crate::config::{CHUNK_SIZE_BASE, MAX_RETRIES};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::compress::{self, Codec, CompressMode};
+use crate::crypto::{Channel, Stream};
+use crate::error::{Error, Result};
+use crate::handshake::{self, Hello, JoinRequest, SessionHello, SessionId, SessionKeys, VerifiedPeer};
+use crate::identity::{self, LocalIdentity, PeerIdentity};
+use crate::inbox::Inbox;
+use crate::journal::Journal;
+use crate::known_peers::{Expected, KnownPeers, PeerStatus};
+use crate::manifest::{relative_path, Entry, EntryKind, Layout, Manifest};
+use crate::network;
+use crate::policy::{Decision, Offer, OfferHandler, Policy};
+use crate::protocol::{read_frame, read_message, write_frame, write_message, FrameHeader, FrameKind, Message, FLAG_COMPRESSED};
+use crate::config::{ACK_WINDOW, CHUNK_SIZE_BASE, DECISION_TIMEOUT, IO_TIMEOUT, JOURNAL_INTERVAL, MAX_RETRIES, MAX_STREAMS, PAIRING_ATTEMPTS, RETRY_BACKOFF_MS, SERVER_PORT, STREAMS, THREADS};
+
+/// What happened during a transfer, as it happens
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    /// Sender is walking and hashing its inputs
+    Scanning { inputs: usize },
+    /// Receiver is waiting for a sender on `addr`
+    Listening { addr: SocketAddr },
+    /// A connection to or from `peer` was accepted; there is one per attempt
+    Connected { peer: SocketAddr },
+    /// Receiver dropped a connection from an address outside its trusted peers
+    Refused { peer: SocketAddr },
+    /// Receiver dropped a connection that didn't get through the handshake (not an SPL
+    /// sender, a wrong pairing code, a refused key) and keeps listening
+    Dropped { peer: SocketAddr, error: String },
+    /// The other side proved its identity key during the handshake; `status` says
+    /// whether that key is new, known, or changed since last time
+    PeerVerified { peer: PeerIdentity, status: PeerStatus },
+    /// What is being transferred. The receiver reports it once per connection,
+    /// along with the directory the entries land in.
+    Manifest { entries: usize, total_bytes: u64, total_chunks: u64, destination: Option<PathBuf> },
+    /// Sender offered the manifest and is waiting for the receiver to accept it
+    Offered,
+    /// Receiver turned down an offer from `peer` and keeps listening
+    Declined { peer: PeerIdentity, reason: String },
+    /// Both sides agreed on the manifest; chunks from an earlier attempt are skipped.
+    /// `streams` is how many connections the chunks are spread over (on the receiver,
+    /// how many it granted).
+    Started { total_chunks: u64, resumed_chunks: u64, codec: Option<Codec>, streams: u8 },
+    /// Bytes confirmed on the receiver's disk so far, counting resumed ones
+    Progress { transferred: u64, total: u64, elapsed: Duration },
+    /// A chunk failed to authenticate or decode on the receiver and is being resent
+    ChunkRejected { index: u64, reason: String },
+    /// The connection dropped. The sender retries after `delay`; the receiver
+    /// (`delay` of `None`) waits for the sender to come back.
+    Retrying { attempt: u8, max: u8, delay: Option<Duration>, error: String },
+    /// Every chunk is in and the receiver is checking the files against the manifest
+    Verifying,
+    /// `path` was left out of the transfer: a sender's input that isn't a file, directory or
+    /// symlink, or a symlink the receiver's platform can't create
+    Skipped { path: PathBuf, reason: String },
+    /// Serving only: a session finished and its files are verified on disk
+    Completed { report: TransferReport },
+    /// Serving only: the session with `peer` failed; the server keeps running
+    Failed { peer: SocketAddr, error: String },
+}
+
+/// Callback receiving every `TransferEvent` of a transfer
+pub type EventHandler = Arc<dyn Fn(TransferEvent) + Send + Sync>;
+
+/// How to send
+#[derive(Clone)]
+pub struct SendOptions {
+    /// Code displayed by a receiver in pairing mode
+    pub pairing_code: Option<String>,
+    pub compression: CompressMode,
+    /// Bytes per chunk
+    pub chunk_size: usize,
+    /// Chunks read, compressed and encrypted at the same time
+    pub threads: usize,
+    /// TCP connections to spread chunks over, if the receiver grants that many
+    pub streams: u8,
+    /// Reconnects before giving up
+    pub max_retries: u8,
+    /// Key this device proves itself with
+    pub identity: LocalIdentity,
+    /// Where receivers' keys are remembered and checked; `None` skips the check
+    pub known_peers: Option<KnownPeers>,
+    /// The device the receiver has to be, e.g. as announced in discovery. The host
+    /// defaults to the one sent to.
+    pub expected: Expected,
+    pub on_event: Option<EventHandler>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        SendOptions {
+            pairing_code: None,
+            compression: CompressMode::default(),
+            chunk_size: CHUNK_SIZE_BASE,
+            threads: THREADS,
+            streams: STREAMS,
+            max_retries: MAX_RETRIES,
+            identity: LocalIdentity::ephemeral(identity::hostname()),
+            known_peers: None,
+            expected: Expected::default(),
+            on_event: None,
+        }
+    }
+}
+
+/// How to receive
+#[derive(Clone)]
+pub struct ReceiveOptions {
+    /// TCP port to listen on
+    pub port: u16,
+    /// Only accept a sender that enters this code
+    pub pairing_code: Option<String>,
+    /// Reconnects to wait for before giving up
+    pub max_retries: u8,
+    /// Most TCP connections one sender may spread its chunks over
+    pub streams: u8,
+    /// Only accept connections from these addresses; empty accepts anyone
+    pub trusted_peers: Vec<IpAddr>,
+    /// Key this device proves itself with
+    pub identity: LocalIdentity,
+    /// Where senders' keys are remembered and checked; `None` skips the check
+    pub known_peers: Option<KnownPeers>,
+    /// Rules every offer must pass before it is accepted
+    pub policy: Policy,
+    /// Asked about offers that pass the policy; `None` accepts them
+    pub on_offer: Option<OfferHandler>,
+    pub on_event: Option<EventHandler>,
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        ReceiveOptions {
+            port: SERVER_PORT,
+            pairing_code: None,
+            max_retries: MAX_RETRIES,
+            streams: STREAMS,
+            trusted_peers: Vec::new(),
+            identity: LocalIdentity::ephemeral(identity::hostname()),
+            known_peers: None,
+            policy: Policy::default(),
+            on_offer: None,
+            on_event: None,
+        }
+    }
+}
+
+/// Outcome of a completed transfer
+#[derive(Clone, Debug)]
+pub struct TransferReport {
+    /// Everything that was transferred
+    pub manifest: Manifest,
+    /// Address of the other side on the final, successful connection
+    pub peer: SocketAddr,
+    /// Who the other side proved to be
+    pub peer_identity: PeerIdentity,
+    /// Receiving side only: the saved file for a lone file, otherwise the target directory
+    pub saved_to: Option<PathBuf>,
+    /// Connections it took, 1 if nothing was interrupted
+    pub attempts: u8,
+    pub elapsed: Duration,
+}
 
 /// Represents a chunk to send
+#[derive(Clone, Copy)]
 struct Chunk {
-    index: usize,
+    index: u64,
+    /// Position of the source file in the manifest
+    entry: usize,
     offset: u64,
     size: usize,
+    /// Codec to try on this chunk, if compression is on and the file looks compressible
+    codec: Option<Codec>,
+    /// Times this chunk was sent before and rejected
+    attempt: u8,
 }
 
-/// Send file in parallel chunks
-pub fn send_file(filename: &str, ip: &str, key: &[u8]) {
-    let file_size = std::fs::metadata(filename).unwrap().len() as usize;
-    let chunk_size = CHUNK_SIZE_BASE;
-    let total_chunks = (file_size + chunk_size - 1) / chunk_size;
+/// What stays fixed across reconnects of one outgoing transfer
+struct Outgoing {
+    manifest: Manifest,
+    layout: Layout,
+    /// Local path of every manifest entry
+    sources: Vec<PathBuf>,
+    compression: CompressMode,
+}
 
-    println!("📤 Sending '{}' ({:.2} MB) → {}", filename, file_size as f64 / 1024.0 / 1024.0, ip);
-    println!("Total chunks: {}", total_chunks);
+/// Send files and directory trees to `peer` (`host:port`), preparing several chunks
+/// in parallel. A dropped connection is re-established with backoff and resumes
+/// from the chunks the receiver has.
+pub async fn send(paths: &[PathBuf], peer: &str, options: &SendOptions) -> Result<TransferReport> {
+    let started = Instant::now();
+    emit(&options.on_event, TransferEvent::Scanning { inputs: paths.len() });
+    let inputs = paths.to_vec();
+    let chunk_size = options.chunk_size as u64;
+    let (manifest, sources, skipped) = blocking(move || Manifest::build(&inputs, chunk_size)).await?;
+    for path in skipped {
+        emit(&options.on_event, TransferEvent::Skipped { path, reason: "not a regular file, directory or symlink".into() });
+    }
+    // Fail here rather than have the receiver refuse it
+    manifest.validate()?;
+    let layout = manifest.layout();
+    emit(&options.on_event, TransferEvent::Manifest {
+        entries: manifest.entries.len(),
+        total_bytes: manifest.total_size(),
+        total_chunks: layout.chunk_count(),
+        destination: None,
+    });
 
-    let mut stream = TcpStream::connect(format!("{}:{}", ip, crate::config::SERVER_PORT)).unwrap();
+    let outgoing = Outgoing { manifest, layout, sources, compression: options.compression };
+    let mut attempt = 0;
+    let (peer_addr, peer_identity) = loop {
+        match send_session(&outgoing, peer, options).await {
+            Ok(connected) => break connected,
+            Err(e) if attempt < options.max_retries && is_retryable(&e) => {
+                attempt += 1;
+                let delay = backoff(attempt);
+                emit(&options.on_event, TransferEvent::Retrying { attempt, max: options.max_retries, delay: Some(delay), error: e.to_string() });
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => return Err(e),
+        }
+    };
 
-    // Send AES key first
-    stream.write_all(&(key.len() as u32).to_be_bytes()).unwrap();
-    stream.write_all(key).unwrap();
+    Ok(TransferReport {
+        manifest: outgoing.manifest,
+        peer: peer_addr,
+        peer_identity,
+        saved_to: None,
+        attempts: attempt.saturating_add(1),
+        elapsed: started.elapsed(),
+    })
+}
 
-    let file = Arc::new(Mutex::new(File::open(filename).unwrap()));
-    let progress = Arc::new(Mutex::new(0usize));
-    let start = Instant::now();
+/// One session's worth of sending: handshake, manifest, then every chunk the receiver
+/// lacks, spread over as many connections as it grants and keeping at most `ACK_WINDOW`
+/// unacknowledged bytes in flight per connection
+async fn send_session(outgoing: &Outgoing, peer: &str, options: &SendOptions) -> Result<(SocketAddr, PeerIdentity)> {
+    let Outgoing { manifest, layout, sources, compression } = outgoing;
+    let total_chunks = layout.chunk_count();
+    let total_size = manifest.total_size();
+
+    let mut stream = timed(TcpStream::connect(peer))
+        .await
+        .map_err(|e| Error::PeerNotFound(format!("Can't reach {}: {}", peer, e)))?;
+    let peer_addr = stream.peer_addr()?;
+    emit(&options.on_event, TransferEvent::Connected { peer: peer_addr });
+
+    // Fresh keys for every connection; the key itself never crosses the wire. The key
+    // that answers is remembered for the host and port we asked for, not for whatever it claims to be.
+    let expected = Expected {
+        host: options.expected.host.clone().or_else(|| network::Target::parse(peer, peer_addr.port()).map(|target| target.to_string())),
+        ..options.expected.clone()
+    };
+    let (keys, verified) = timed(handshake::initiate(
+        &mut stream,
+        options.pairing_code.as_deref(),
+        &options.identity,
+        options.known_peers.as_ref(),
+        &expected,
+    ))
+    .await?;
+    emit(&options.on_event, TransferEvent::PeerVerified { peer: verified.identity.clone(), status: verified.status });
+    let outbound = Arc::new(Channel::new(&keys.sender_key)?);
+    let inbound = Channel::new(&keys.receiver_key)?;
+
+    // Tell the receiver what's coming before any chunk
+    let streams = (options.streams.clamp(1, MAX_STREAMS) as u64).min(total_chunks.max(1)) as u8;
+    let offer = Message::Manifest { manifest: manifest.clone(), codecs: compression.offer(), streams };
+    timed(write_message(&mut stream, &outbound.control, 0, &offer)).await?;
+    emit(&options.on_event, TransferEvent::Offered);
 
-    // Create chunk queue
-    let chunks: Vec<_> = (0..total_chunks).map(|i| Chunk {
-        index: i,
-        offset: (i * chunk_size) as u64,
-        size: if (i + 1) * chunk_size > file_size { file_size - i * chunk_size } else { chunk_size },
+    // The receiver answers with whatever it kept from an interrupted attempt,
+    // possibly after asking its user, so allow longer than a stalled socket
+    let reply = tokio::time::timeout(Duration::from_secs(DECISION_TIMEOUT), read_message(&mut stream, &inbound.control, 0))
+        .await
+        .map_err(|_| Error::PeerRejected("the receiver didn't accept in time".into()))??;
+    let (have, codec, granted, session) = match reply {
+        Message::Ready { have, codec, streams: granted, session } if have.count() == total_chunks && (1..=streams).contains(&granted) => {
+            (have, codec, granted, session)
+        }
+        Message::Declined { reason } => return Err(Error::PeerRejected(reason)),
+        other => return Err(Error::Protocol(format!("Unexpected reply to manifest: {:?}", other))),
+    };
+    if codec.is_none() && *compression != CompressMode::Auto && *compression != CompressMode::Off {
+        return Err(Error::PeerRejected(format!("Receiver does not support {:?} compression", compression)));
+    }
+
+    // Create chunk queue from whatever the receiver is still missing
+    let mut queue: Vec<_> = have.missing().map(|i| {
+        let span = layout.span(i);
+        let codec = codec.filter(|_| compression.worth_trying(&sources[span.entry]));
+        Chunk { index: i, entry: span.entry, offset: span.offset, size: span.length as usize, codec, attempt: 0 }
     }).collect();
-    let chunk_queue = Arc::new(Mutex::new(chunks));
-
-    // Threads
-    let mut handles = vec![];
-    let thread_count = 4.min(total_chunks); // max 4 threads or total_chunks
-
-    for _ in 0..thread_count {
-        let queue = Arc::clone(&chunk_queue);
-        let file = Arc::clone(&file);
-        let progress = Arc::clone(&progress);
-        let mut stream = stream.try_clone().unwrap();
-        let key = key.to_vec();
-
-        let handle = thread::spawn(move || {
-            while let Some(chunk) = {
-                let mut q = queue.lock().unwrap();
-                q.pop()
-            } {
-                let mut buf = vec![0u8; chunk.size];
-                {
-                    let mut f = file.lock().unwrap();
-                    f.seek(SeekFrom::Start(chunk.offset)).unwrap();
-                    f.read_exact(&mut buf).unwrap();
+
+    // Extra data connections join by quoting the session; no point in more than there are chunks
+    let mut connections = vec![stream];
+    for lane in 1..granted.min(queue.len().clamp(1, MAX_STREAMS as usize) as u8) {
+        connections.push(join_lane(peer_addr, &keys.join_key, &session, lane).await?);
+    }
+    let streams = connections.len() as u8;
+    emit(&options.on_event, TransferEvent::Started { total_chunks, resumed_chunks: have.len(), codec, streams });
+
+    // Each connection writes whichever prepared chunk is next, so faster ones carry more,
+    // and reads its own acknowledgements on a task, since a half-read frame can't be abandoned.
+    // Both report to `acks`.
+    let (ack_tx, mut acks) = mpsc::unbounded_channel();
+    let (frame_tx, frames) = mpsc::channel(connections.len());
+    let frames = Arc::new(tokio::sync::Mutex::new(frames));
+    let mut lanes = JoinSet::new();
+    for (lane, stream) in (0..).zip(connections) {
+        let (reader, writer) = stream.into_split();
+        // The Ready reply was message 0 of the first connection
+        let first_seq = if lane == 0 { 1 } else { 0 };
+        lanes.spawn(read_acks(reader, Channel::lane(&keys.receiver_key, lane)?, first_seq, ack_tx.clone()));
+        lanes.spawn(write_chunks(writer, frames.clone(), ack_tx.clone()));
+    }
+    drop(ack_tx);
+    let window = ACK_WINDOW * streams as usize;
+
+    let mut unacked: HashMap<u64, Chunk> = HashMap::new();
+    let mut in_flight = 0; // bytes being prepared or awaiting acknowledgement
+    let mut preparing = JoinSet::new();
+    let mut acked = layout.bytes_in(&have);
+    let start = Instant::now();
+
+    let result: Result<()> = async {
+        while !queue.is_empty() || !unacked.is_empty() {
+            // Prepare more chunks while the window has room; a lone chunk may always go
+            while preparing.len() < options.threads.max(1) {
+                let Some(&chunk) = queue.last() else { break };
+                if in_flight > 0 && in_flight + chunk.size > window {
+                    break;
                 }
+                queue.pop();
+                in_flight += chunk.size;
+                unacked.insert(chunk.index, chunk);
+                let cipher = outbound.clone();
+                let source = sources[chunk.entry].clone();
+                let last = chunk.index + 1 == total_chunks;
+                preparing.spawn_blocking(move || seal_chunk(&cipher.chunks, &source, &chunk, last));
+            }
 
-                let mut retries = 0;
-                loop {
-                    let encrypted = encrypt_chunk(&key, &buf);
-                    let len_bytes = (encrypted.len() as u32).to_be_bytes();
-
-                    if stream.write_all(&len_bytes).is_err() || stream.write_all(&encrypted).is_err() {
-                        if retries < MAX_RETRIES {
-                            retries += 1;
-                            eprintln!("⚠ Retry {} for chunk {}", retries, chunk.index);
-                            thread::sleep(Duration::from_millis(100));
-                            continue;
-                        } else {
-                            panic!("❌ Failed to send chunk {} after {} retries", chunk.index, MAX_RETRIES);
+            tokio::select! {
+                Some(prepared) = preparing.join_next(), if !preparing.is_empty() => {
+                    let prepared = prepared.map_err(io::Error::other)?;
+                    frame_tx.send(prepared?).await.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Every connection to the receiver closed"))?;
+                }
+                reply = tokio::time::timeout(Duration::from_secs(IO_TIMEOUT), acks.recv()) => {
+                    let reply = reply
+                        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding"))?
+                        .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Receiver closed the connection").into()))?;
+                    match reply {
+                        Message::Ack { index } => {
+                            if let Some(chunk) = unacked.remove(&index) {
+                                in_flight -= chunk.size;
+                                acked += chunk.size as u64;
+                                emit(&options.on_event, TransferEvent::Progress { transferred: acked, total: total_size, elapsed: start.elapsed() });
+                            }
+                        }
+                        Message::Nack { index, reason } => {
+                            if let Some(mut chunk) = unacked.remove(&index) {
+                                in_flight -= chunk.size;
+                                // Each resend is sealed under its attempt number, which must never wrap
+                                chunk.attempt = match chunk.attempt.checked_add(1) {
+                                    Some(attempt) if attempt <= options.max_retries => attempt,
+                                    _ => {
+                                        let times = u16::from(chunk.attempt) + 1;
+                                        return Err(Error::Integrity(format!("Receiver rejected chunk {} {} times: {}", index, times, reason)));
+                                    }
+                                };
+                                emit(&options.on_event, TransferEvent::ChunkRejected { index, reason });
+                                queue.push(chunk);
+                            }
+                        }
+                        other => {
+                            return Err(Error::Protocol(format!("Unexpected message during transfer: {:?}", other)));
                         }
                     }
-                    break;
                 }
+            }
+        }
+        Ok(())
+    }.await;
+
+    // Dropping the tasks closes every connection
+    drop(lanes);
+    result.map(|_| (peer_addr, verified.identity))
+}
+
+/// A chunk frame ready to go out on whichever connection is free
+type SealedChunk = (FrameHeader, Vec<u8>);
+
+/// Open data connection `lane` of `session` on the receiver at `addr`
+async fn join_lane(addr: SocketAddr, join_key: &[u8; 32], session: &SessionId, lane: u8) -> Result<TcpStream> {
+    let mut stream = timed(TcpStream::connect(addr)).await?;
+    timed(handshake::join(&mut stream, join_key, session, lane)).await?;
+    Ok(stream)
+}
+
+/// Write prepared chunks to one connection, taking whichever is next in line, until
+/// there are no more or the connection fails
+async fn write_chunks(
+    mut writer: OwnedWriteHalf,
+    frames: Arc<tokio::sync::Mutex<mpsc::Receiver<SealedChunk>>>,
+    errors: mpsc::UnboundedSender<Result<Message>>,
+) {
+    loop {
+        let next = frames.lock().await.recv().await;
+        let Some((header, sealed)) = next else { break };
+        if let Err(e) = timed(write_frame(&mut writer, &header, &sealed)).await {
+            let _ = errors.send(Err(e));
+            break;
+        }
+    }
+}
+
+/// Forward the receiver's control messages on one connection, numbered from `first_seq`,
+/// until the connection fails
+async fn read_acks(mut reader: OwnedReadHalf, inbound: Channel, first_seq: u64, acks: mpsc::UnboundedSender<Result<Message>>) {
+    // No timeout here: a connection may idle while the others carry the chunks
+    for seq in first_seq.. {
+        let message = read_message(&mut reader, &inbound.control, seq).await;
+        let failed = message.is_err();
+        if acks.send(message).is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Read, compress and encrypt one chunk
+fn seal_chunk(cipher: &Stream, source: &Path, chunk: &Chunk, last: bool) -> Result<SealedChunk> {
+    let mut buf = vec![0u8; chunk.size];
+    let mut f = File::open(source)?;
+    f.seek(SeekFrom::Start(chunk.offset))?;
+    f.read_exact(&mut buf)?;
 
-                let mut prog = progress.lock().unwrap();
-                *prog += chunk.size;
-                print_progress(*prog, file_size, start);
+    // Compress before encrypting, falling back to raw bytes when it doesn't pay off
+    let (flags, payload) = match chunk.codec.and_then(|c| compress::compress(c, &buf)) {
+        Some(packed) => (FLAG_COMPRESSED, packed),
+        None => (0, buf),
+    };
+    let header = FrameHeader { attempt: chunk.attempt, ..FrameHeader::chunk(chunk.index, chunk.offset, chunk.size as u32, flags) };
+    let sealed = cipher.seal(chunk.index, chunk.attempt, last, &payload, &header.to_bytes())?;
+    Ok((header, sealed))
+}
+
+/// Network hiccups (including a peer that isn't listening yet) are worth a fresh session;
+/// protocol, integrity and authentication failures are not, since retrying would fail the same way
+fn is_retryable(e: &Error) -> bool {
+    let Error::Io(e) = e else { return matches!(e, Error::PeerNotFound(_)) };
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+    )
+}
+
+/// Exponential backoff: base, 2×base, 4×base… capped at ten seconds
+fn backoff(attempt: u8) -> Duration {
+    let ms = RETRY_BACKOFF_MS.saturating_mul(1 << (attempt.saturating_sub(1)).min(16));
+    Duration::from_millis(ms.min(10_000))
+}
+
+/// Fail with `TimedOut` if the peer leaves `io` hanging for longer than `IO_TIMEOUT`
+async fn timed<T, E: Into<Error>>(io: impl Future<Output = std::result::Result<T, E>>) -> Result<T> {
+    tokio::time::timeout(Duration::from_secs(IO_TIMEOUT), io)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding"))?
+        .map_err(Into::into)
+}
+
+/// Run blocking file or CPU work on tokio's blocking pool
+async fn blocking<T, E>(work: impl FnOnce() -> std::result::Result<T, E> + Send + 'static) -> Result<T>
+where
+    T: Send + 'static,
+    E: Into<Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(work).await.map_err(io::Error::other)?.map_err(Into::into)
+}
+
+fn emit(handler: &Option<EventHandler>, event: TransferEvent) {
+    if let Some(handler) = handler {
+        handler(event);
+    }
+}
+
+/// Receive files into `dest`. A single file is saved as `dest` (or inside it, if
+/// `dest` is a directory); anything else is recreated as a tree under the `dest` directory.
+/// Progress is journaled alongside so an interrupted transfer can resume, and
+/// after a dropped connection we keep listening for the sender to reconnect.
+pub async fn receive(dest: &Path, options: &ReceiveOptions) -> Result<TransferReport> {
+    let started = Instant::now();
+    let listener = TcpListener::from_std(network::listen_dual_stack(options.port)?)?;
+    emit(&options.on_event, TransferEvent::Listening { addr: listener.local_addr()? });
+
+    // Dropping the set stops accepting, whichever way we return
+    let joinable = Joinable::default();
+    let (arrival_tx, mut arrivals) = mpsc::unbounded_channel();
+    let mut acceptor = JoinSet::new();
+    acceptor.spawn(accept(listener, Arc::new(options.clone()), joinable.clone(), arrival_tx));
+
+    let mut failures: u8 = 0;
+    let mut wrong_codes = 0;
+    let mut accepted = None;
+    while let Some(Arrival { peer, greeted }) = arrivals.recv().await {
+        // Anyone can connect, so only a sender that got through the handshake may end the wait or use up retries
+        let greeted = match greeted {
+            Ok(greeted) => greeted,
+            Err(e) => {
+                dropped(options, peer, e, &mut wrong_codes)?;
+                continue;
+            }
+        };
+        match receive_session(greeted, &joinable, &|_| Ok(dest.to_path_buf()), options, &mut accepted).await {
+            Ok(Some((manifest, saved_to, peer_identity))) => {
+                return Ok(TransferReport {
+                    manifest,
+                    peer,
+                    peer_identity,
+                    saved_to: Some(saved_to),
+                    attempts: failures.saturating_add(1),
+                    elapsed: started.elapsed(),
+                });
+            }
+            Ok(None) => continue,
+            Err(e) if failures < options.max_retries && is_retryable(&e) => {
+                failures += 1;
+                emit(&options.on_event, TransferEvent::Retrying { attempt: failures, max: options.max_retries, delay: None, error: e.to_string() });
             }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other("Stopped accepting connections").into())
+}
+
+/// Keep receiving from any number of senders at once, each session on its own task,
+/// saving into the inbox folder of whoever sent it. Reconnecting senders resume from the
+/// journal left in that folder. Only returns if the port can't be listened on, or
+/// once wrong pairing codes have used up every guess.
+pub async fn serve(inbox: &Inbox, options: &ReceiveOptions) -> Result<()> {
+    let listener = TcpListener::from_std(network::listen_dual_stack(options.port)?)?;
+    emit(&options.on_event, TransferEvent::Listening { addr: listener.local_addr()? });
+
+    let inbox = Arc::new(inbox.clone());
+    let options = Arc::new(options.clone());
+    let joinable = Joinable::default();
+    let (arrival_tx, mut arrivals) = mpsc::unbounded_channel();
+    let mut acceptor = JoinSet::new();
+    acceptor.spawn(accept(listener, options.clone(), joinable.clone(), arrival_tx));
+
+    let mut wrong_codes = 0;
+    while let Some(Arrival { peer, greeted }) = arrivals.recv().await {
+        let greeted = match greeted {
+            Ok(greeted) => greeted,
+            Err(e) => {
+                dropped(&options, peer, e, &mut wrong_codes)?;
+                continue;
+            }
+        };
+        let (inbox, options, joinable) = (inbox.clone(), options.clone(), joinable.clone());
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let folder = |sender: &PeerIdentity| inbox.folder(sender);
+            let event = match receive_session(greeted, &joinable, &folder, &options, &mut None).await {
+                Ok(Some((manifest, saved_to, peer_identity))) => {
+                    let report = TransferReport {
+                        manifest,
+                        peer,
+                        peer_identity,
+                        saved_to: Some(saved_to),
+                        attempts: 1,
+                        elapsed: started.elapsed(),
+                    };
+                    TransferEvent::Completed { report }
+                }
+                Ok(None) => return,
+                Err(e) => TransferEvent::Failed { peer, error: e.to_string() },
+            };
+            emit(&options.on_event, event);
         });
-        handles.push(handle);
     }
+    Err(io::Error::other("Stopped accepting connections").into())
+}
 
-    for h in handles { h.join().unwrap(); }
+/// Report a connection that didn't get through the handshake. Every wrong pairing code is
+/// an online guess at it, so after a few of them this fails and the receiver stops.
+fn dropped(options: &ReceiveOptions, peer: SocketAddr, error: Error, wrong_codes: &mut u8) -> Result<()> {
+    let wrong_code = options.pairing_code.is_some() && matches!(error, Error::Authentication(_));
+    emit(&options.on_event, TransferEvent::Dropped { peer, error: error.to_string() });
+    if wrong_code {
+        *wrong_codes += 1;
+        if *wrong_codes >= PAIRING_ATTEMPTS {
+            return Err(Error::Authentication(format!("{} wrong pairing codes; start again for a new code", wrong_codes)));
+        }
+    }
+    Ok(())
+}
 
-    println!("\n✅ Transfer complete");
+/// A connection from a sender opening a new session
+struct Arrival {
+    peer: SocketAddr,
+    /// The sender once it got through the handshake, or why it didn't
+    greeted: Result<Greeted>,
 }
 
-/// Receive file in parallel chunks (resume supported)
-pub fn receive_file(outfile: &str) {
-    use std::net::TcpListener;
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", crate::config::SERVER_PORT)).unwrap();
-    println!("📥 Receiver ready on port {}, saving to {}", crate::config::SERVER_PORT, outfile);
+/// Sessions that extra data connections may join, by the ID they quote
+type Joinable = Arc<Mutex<HashMap<SessionId, Lanes>>>;
 
-    let (mut stream, addr) = listener.accept().unwrap();
-    println!("✅ Connection from {}", addr);
+/// The extra data connections a session granted, and where to hand them over
+struct Lanes {
+    join_key: [u8; 32],
+    /// Lanes 1 up to (not including) this one may join
+    streams: u8,
+    /// Lanes already joined; one joining twice would repeat its control nonces
+    joined: HashSet<u8>,
+    handover: mpsc::UnboundedSender<(u8, TcpStream)>,
+}
 
-    // Receive AES key
-    let mut len_buf = [0u8;4];
-    stream.read_exact(&mut len_buf).unwrap();
-    let key_len = u32::from_be_bytes(len_buf) as usize;
-    let mut key = vec![0u8; key_len];
-    stream.read_exact(&mut key).unwrap();
+/// Keeps a session joinable until dropped
+struct Registration<'a> {
+    joinable: &'a Joinable,
+    session: SessionId,
+}
 
-    let mut file = OpenOptions::new().create(true).write(true).open(outfile).unwrap();
-    let start = Instant::now();
-    let mut total_received = 0usize;
+impl<'a> Registration<'a> {
+    fn new(joinable: &'a Joinable, session: SessionId, lanes: Lanes) -> Self {
+        joinable.lock().unwrap_or_else(|e| e.into_inner()).insert(session, lanes);
+        Registration { joinable, session }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.joinable.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.session);
+    }
+}
 
+/// Accept connections until aborted. Addresses outside the trusted peers are dropped,
+/// data connections are handed to the session they join, and new sessions go to
+/// `arrivals` once their handshake is over. Each handshake runs on its own task, so a
+/// silent peer holds up no one.
+async fn accept(listener: TcpListener, options: Arc<ReceiveOptions>, joinable: Joinable, arrivals: mpsc::UnboundedSender<Arrival>) {
+    let guesses = Arc::new(AtomicU8::new(PAIRING_ATTEMPTS));
     loop {
-        let mut len_buf = [0u8;4];
-        if stream.read_exact(&mut len_buf).is_err() { break; }
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut encrypted = vec![0u8; len];
-        if stream.read_exact(&mut encrypted).is_err() { break; }
-
-        let mut retries = 0;
-        loop {
-            match decrypt_chunk(&key, &encrypted) {
-                Ok(data) => {
-                    file.write_all(&data).unwrap();
-                    total_received += data.len();
-                    print_progress(total_received, total_received, start);
-                    break;
-                },
-                Err(_) => {
-                    if retries < MAX_RETRIES {
-                        retries += 1;
-                        eprintln!("⚠ Retry {} for chunk decryption", retries);
-                        thread::sleep(Duration::from_millis(50));
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(_) => {
+                // Usually out of file descriptors; wait for sessions to finish rather than exit
+                tokio::time::sleep(Duration::from_millis(RETRY_BACKOFF_MS)).await;
+                continue;
+            }
+        };
+        let peer = network::canonical(peer);
+        if !options.trusted_peers.is_empty() && !options.trusted_peers.contains(&peer.ip()) {
+            emit(&options.on_event, TransferEvent::Refused { peer });
+            continue;
+        }
+
+        let (options, joinable, arrivals, guesses) = (options.clone(), joinable.clone(), arrivals.clone(), guesses.clone());
+        tokio::spawn(async move {
+            let greeted = match timed(handshake::read_hello(&mut stream)).await {
+                Ok(Hello::Join(request)) => return join_session(stream, request, &joinable).await,
+                Ok(Hello::Session(hello)) => greet(stream, peer, hello, &options, &guesses).await,
+                Err(e) => Err(e),
+            };
+            let _ = arrivals.send(Arrival { peer, greeted });
+        });
+    }
+}
+
+/// Hand a data connection to the session it asks to join, if that session granted its lane
+async fn join_session(mut stream: TcpStream, request: JoinRequest, joinable: &Joinable) {
+    let handover = joinable.lock().unwrap_or_else(|e| e.into_inner()).get_mut(&request.session).and_then(|lanes| {
+        let granted = (1..lanes.streams).contains(&request.lane) && request.verify(&lanes.join_key);
+        (granted && lanes.joined.insert(request.lane)).then(|| lanes.handover.clone())
+    });
+    if timed(request.answer(&mut stream, handover.is_some())).await.is_ok() {
+        if let Some(handover) = handover {
+            let _ = handover.send((request.lane, stream));
+        }
+    }
+}
+
+/// A new session's first connection, once the sender got through the handshake
+struct Greeted {
+    stream: TcpStream,
+    peer: SocketAddr,
+    keys: SessionKeys,
+    verified: VerifiedPeer,
+}
+
+/// Finish the handshake `hello` opened; fresh keys for every connection. When pairing, each
+/// attempt holds one of the `guesses` while it runs and a wrong code uses it up, so guesses
+/// made at the same time can't get past the limit either.
+async fn greet(mut stream: TcpStream, peer: SocketAddr, hello: SessionHello, options: &ReceiveOptions, guesses: &AtomicU8) -> Result<Greeted> {
+    emit(&options.on_event, TransferEvent::Connected { peer });
+    let pairing = options.pairing_code.is_some();
+    if pairing && guesses.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_err() {
+        return Err(io::Error::other("Another sender is already trying the pairing code").into());
+    }
+    let responded = timed(handshake::respond(
+        &mut stream,
+        hello,
+        options.pairing_code.as_deref(),
+        &options.identity,
+        options.known_peers.as_ref(),
+    ))
+    .await;
+    if pairing && !matches!(responded, Err(Error::Authentication(_))) {
+        guesses.fetch_add(1, Ordering::SeqCst);
+    }
+    let (keys, verified) = responded?;
+    emit(&options.on_event, TransferEvent::PeerVerified { peer: verified.identity.clone(), status: verified.status.clone() });
+    Ok(Greeted { stream, peer, keys, verified })
+}
+
+/// Handle one session from a sender that got through the handshake, until its files are verified on disk.
+/// `dest` picks the destination once the sender has identified itself.
+/// Returns the manifest, where it was saved and who sent it, or `None` if the offer was declined.
+/// `accepted` remembers the last accepted offer, so a reconnecting sender isn't asked about twice.
+async fn receive_session(
+    greeted: Greeted,
+    joinable: &Joinable,
+    dest: &(dyn Fn(&PeerIdentity) -> io::Result<PathBuf> + Sync),
+    options: &ReceiveOptions,
+    accepted: &mut Option<(PeerIdentity, Manifest)>,
+) -> Result<Option<(Manifest, PathBuf, PeerIdentity)>> {
+    let Greeted { mut stream, peer: addr, keys, verified } = greeted;
+    let inbound = Arc::new(Channel::new(&keys.sender_key)?);
+    let outbound = Channel::new(&keys.receiver_key)?;
+
+    let (manifest, codecs, streams) = match timed(read_message(&mut stream, &inbound.control, 0)).await? {
+        Message::Manifest { manifest, codecs, streams } => (manifest, codecs, streams),
+        other => return Err(Error::Protocol(format!("Expected a manifest, got {:?}", other))),
+    };
+    manifest.validate()?;
+
+    let offer = Offer { addr, peer: verified.identity, status: verified.status, manifest };
+    if let Decision::Decline(reason) = decide(&offer, options, accepted).await? {
+        timed(write_message(&mut stream, &outbound.control, 0, &Message::Declined { reason: reason.clone() })).await?;
+        emit(&options.on_event, TransferEvent::Declined { peer: offer.peer, reason });
+        return Ok(None);
+    }
+    *accepted = Some((offer.peer.clone(), offer.manifest.clone()));
+    let Offer { peer: sender, manifest, .. } = offer;
+
+    let destination = Destination::resolve(&dest(&sender)?, &manifest)?;
+    let layout = manifest.layout();
+    let total_chunks = layout.chunk_count();
+    emit(&options.on_event, TransferEvent::Manifest {
+        entries: manifest.entries.len(),
+        total_bytes: manifest.total_size(),
+        total_chunks,
+        destination: Some(destination.root.clone()),
+    });
+
+    let incoming = Arc::new(Incoming { manifest, layout, destination });
+    let mut journal = blocking({
+        let incoming = incoming.clone();
+        move || incoming.prepare()
+    }).await?;
+
+    // Grant the connections the sender asked for, up to our own limit. Register the
+    // session before answering, since the extra ones connect as soon as they read it.
+    let streams = streams.clamp(1, options.streams.clamp(1, MAX_STREAMS));
+    let session_id = handshake::session_id();
+    let (handover, joins) = mpsc::unbounded_channel();
+    let lanes = Lanes { join_key: keys.join_key, streams, joined: HashSet::new(), handover: handover.clone() };
+    let _registration = Registration::new(joinable, session_id, lanes);
+
+    let codec = compress::negotiate(&codecs);
+    let ready = Message::Ready { have: journal.chunks.clone(), codec, streams, session: session_id };
+    timed(write_message(&mut stream, &outbound.control, 0, &ready)).await?;
+    emit(&options.on_event, TransferEvent::Started { total_chunks, resumed_chunks: journal.chunks.len(), codec, streams });
+    // This connection is handed over like the others, so chunks are read the same way on all
+    let _ = handover.send((0, stream));
+
+    let mut dirty = HashSet::new();
+    let session = Session { incoming: incoming.clone(), chunks: inbound, receiver_key: keys.receiver_key, codec, options };
+    let result = receive_chunks(joins, &session, &mut journal, &mut dirty).await;
+    // Record what landed even if the connection dropped, so the next attempt can resume
+    checkpoint(&journal, &mut dirty).await?;
+    result?;
+
+    emit(&options.on_event, TransferEvent::Verifying);
+    let finished = blocking({
+        let incoming = incoming.clone();
+        move || incoming.destination.finish(&incoming.manifest)
+    }).await;
+    let skipped = match finished {
+        Ok(skipped) => skipped,
+        Err(e) => {
+            // The bytes on disk can't be trusted, so don't let a retry resume from them
+            journal.remove()?;
+            return Err(e);
+        }
+    };
+    journal.remove()?;
+    for path in skipped {
+        emit(&options.on_event, TransferEvent::Skipped { path, reason: "symlinks aren't supported on this platform".into() });
+    }
+
+    let Incoming { manifest, destination, .. } = &*incoming;
+    let saved_to = match &destination.single {
+        Some(path) => path.clone(),
+        None if Destination::lone_file(manifest) => destination.paths[0].clone(),
+        None => destination.root.clone(),
+    };
+    Ok(Some((manifest.clone(), saved_to, sender)))
+}
+
+/// Run `offer` past the policy, then the offer handler unless the same sender's
+/// identical offer was accepted on an earlier connection
+async fn decide(offer: &Offer, options: &ReceiveOptions, accepted: &Option<(PeerIdentity, Manifest)>) -> Result<Decision> {
+    let decision = options.policy.check(offer);
+    let Some(handler) = options.on_offer.clone().filter(|_| decision == Decision::Accept) else {
+        return Ok(decision);
+    };
+    if accepted.as_ref().is_some_and(|(peer, manifest)| *peer == offer.peer && *manifest == offer.manifest) {
+        return Ok(Decision::Accept);
+    }
+    // Handlers may block, e.g. on a prompt
+    let offer = offer.clone();
+    blocking(move || Ok::<_, Error>(handler(&offer))).await
+}
+
+/// A validated manifest and where its entries land, shared with blocking disk work
+struct Incoming {
+    manifest: Manifest,
+    layout: Layout,
+    destination: Destination,
+}
+
+impl Incoming {
+    /// Create the target tree and load the resume journal, trusting it only for files still on disk
+    fn prepare(&self) -> io::Result<Journal> {
+        let Incoming { manifest, layout, destination } = self;
+        destination.prepare(manifest)?;
+
+        let mut journal = Journal::open(destination.journal_path(manifest), manifest.digest(), layout.chunk_count());
+        for (i, entry) in manifest.entries.iter().enumerate() {
+            if let EntryKind::File { .. } = entry.kind {
+                // A journal is only as good as the files it describes
+                if !destination.paths[i].exists() {
+                    layout.chunks_of(i).for_each(|c| journal.chunks.remove(c));
+                }
+                OpenOptions::new().create(true).write(true).truncate(false).open(&destination.paths[i])?;
+            }
+        }
+        Ok(journal)
+    }
+
+    /// Authenticate, decode and write one chunk. The inner error is a reason to ask
+    /// the sender for the chunk again; the outer one is a local failure.
+    fn write_chunk(&self, cipher: &Stream, codec: Option<Codec>, header: FrameHeader, sealed: Vec<u8>) -> io::Result<std::result::Result<PathBuf, String>> {
+        let last = header.index + 1 == self.layout.chunk_count();
+        let data = cipher
+            .open(header.index, header.attempt, last, &sealed, &header.to_bytes())
+            .and_then(|data| unpack(codec, header, data));
+        let data = match data {
+            Ok(data) if data.len() == header.length as usize => data,
+            Ok(_) => return Ok(Err("wrong length".into())),
+            Err(e) => return Ok(Err(e.to_string())),
+        };
+
+        // Chunks may arrive in any order; each one knows where it belongs
+        let path = &self.destination.paths[self.layout.span(header.index).entry];
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(header.offset))?;
+        file.write_all(&data)?;
+        Ok(Ok(path.clone()))
+    }
+}
+
+/// Fixed parameters of one receive session
+struct Session<'a> {
+    incoming: Arc<Incoming>,
+    chunks: Arc<Channel>,
+    /// Key our replies (acknowledgements) to the sender are encrypted with
+    receiver_key: [u8; 32],
+    codec: Option<Codec>,
+    options: &'a ReceiveOptions,
+}
+
+/// A chunk frame read on data connection `lane`, and what became of it: the file it
+/// was written to, or why the sender has to send it again
+struct Arrived {
+    lane: u8,
+    header: FrameHeader,
+    written: std::result::Result<PathBuf, String>,
+}
+
+/// Our half of one data connection, where its chunks are acknowledged
+struct Replies {
+    writer: OwnedWriteHalf,
+    channel: Channel,
+    /// Sequence number of the next control message
+    seq: u64,
+}
+
+impl Replies {
+    async fn send(&mut self, message: &Message) -> Result<()> {
+        timed(write_message(&mut self.writer, &self.channel.control, self.seq, message)).await?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// Read chunk frames from every data connection handed over on `lanes` until the journal
+/// holds every chunk of the manifest. Each chunk is written where it belongs whichever
+/// connection it came in on, then acknowledged on that connection, or asked for again
+/// if it's unusable. Losing any connection ends the session.
+async fn receive_chunks(
+    mut lanes: mpsc::UnboundedReceiver<(u8, TcpStream)>,
+    session: &Session<'_>,
+    journal: &mut Journal,
+    dirty: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let Session { incoming, chunks, receiver_key, codec, options } = session;
+    let Incoming { manifest, layout, .. } = &**incoming;
+    let start = Instant::now();
+    let total_chunks = layout.chunk_count();
+    let total_size = manifest.total_size();
+    let mut total_received = layout.bytes_in(&journal.chunks);
+    let mut since_checkpoint = 0;
+
+    // Frames are read and written to disk on a task per connection; acknowledging stays here
+    let (arrived_tx, mut arrived) = mpsc::unbounded_channel();
+    let mut readers = JoinSet::new();
+    let mut replies = HashMap::new();
+
+    while !journal.chunks.is_complete() {
+        tokio::select! {
+            Some((lane, stream)) = lanes.recv() => {
+                let (reader, writer) = stream.into_split();
+                // The Ready reply was message 0 of the first connection
+                let seq = if lane == 0 { 1 } else { 0 };
+                replies.insert(lane, Replies { writer, channel: Channel::lane(receiver_key, lane)?, seq });
+                readers.spawn(read_chunks(reader, lane, incoming.clone(), chunks.clone(), *codec, arrived_tx.clone()));
+            }
+            next = tokio::time::timeout(Duration::from_secs(IO_TIMEOUT), arrived.recv()) => {
+                let Ok(Some(next)) = next else {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding").into());
+                };
+                let Some(Arrived { lane, header, written }) = next? else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Sender disconnected after {} of {} chunks", journal.chunks.len(), total_chunks),
+                    ).into());
+                };
+                let replies = replies.get_mut(&lane).expect("lanes are registered before they are read");
+                let path = match written {
+                    Ok(path) => path,
+                    Err(reason) => {
+                        emit(&options.on_event, TransferEvent::ChunkRejected { index: header.index, reason: reason.clone() });
+                        replies.send(&Message::Nack { index: header.index, reason }).await?;
                         continue;
-                    } else {
-                        panic!("❌ Decryption failed after {} retries", MAX_RETRIES);
                     }
+                };
+
+                dirty.insert(path);
+                if journal.chunks.insert(header.index) {
+                    total_received += layout.span(header.index).length;
+                    since_checkpoint += 1;
+                }
+                if since_checkpoint >= JOURNAL_INTERVAL {
+                    checkpoint(journal, dirty).await?;
+                    since_checkpoint = 0;
                 }
+                replies.send(&Message::Ack { index: header.index }).await?;
+                emit(&options.on_event, TransferEvent::Progress { transferred: total_received, total: total_size, elapsed: start.elapsed() });
             }
         }
     }
 
-    println!("\n✅ File saved as {}", outfile);
+    // The last acknowledgements may still be on their way over one connection when another
+    // closes, so let the sender hang up first, once it has read them all
+    let mut open = replies.len();
+    while open > 0 {
+        match tokio::time::timeout(Duration::from_secs(IO_TIMEOUT), arrived.recv()).await {
+            Ok(Some(Ok(Some(_)))) => {}
+            Ok(Some(_)) => open -= 1,
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Read chunk frames from one data connection and write each to disk, passing on what
+/// became of it until the connection closes (`None`) or fails
+async fn read_chunks(
+    mut reader: OwnedReadHalf,
+    lane: u8,
+    incoming: Arc<Incoming>,
+    cipher: Arc<Channel>,
+    codec: Option<Codec>,
+    arrived: mpsc::UnboundedSender<Result<Option<Arrived>>>,
+) {
+    loop {
+        let next = read_chunk(&mut reader, lane, &incoming, &cipher, codec).await;
+        let done = !matches!(next, Ok(Some(_)));
+        if arrived.send(next).is_err() || done {
+            break;
+        }
+    }
+}
+
+/// Read one chunk frame, check it against the manifest layout and write it out
+async fn read_chunk(reader: &mut OwnedReadHalf, lane: u8, incoming: &Arc<Incoming>, cipher: &Arc<Channel>, codec: Option<Codec>) -> Result<Option<Arrived>> {
+    // No timeout here: a connection may idle while the others carry the chunks
+    let Some((header, sealed)) = read_frame(reader).await? else { return Ok(None) };
+    let layout = &incoming.layout;
+    if header.kind != FrameKind::Chunk || header.index >= layout.chunk_count() {
+        return Err(Error::Protocol(format!("Unexpected frame for chunk {}", header.index)));
+    }
+    let span = layout.span(header.index);
+    if header.offset != span.offset || header.length as u64 != span.length {
+        return Err(Error::Protocol(format!("Chunk {} does not match the manifest layout", header.index)));
+    }
+
+    let written = blocking({
+        let (incoming, cipher) = (incoming.clone(), cipher.clone());
+        move || incoming.write_chunk(&cipher.chunks, codec, header, sealed)
+    }).await?;
+    Ok(Some(Arrived { lane, header, written }))
+}
+
+/// Decompress a decrypted chunk payload if its header says it was compressed
+fn unpack(codec: Option<Codec>, header: FrameHeader, data: Vec<u8>) -> Result<Vec<u8>> {
+    if header.flags & FLAG_COMPRESSED == 0 {
+        return Ok(data);
+    }
+    let codec = codec.ok_or_else(|| Error::Protocol("Compressed chunk but no codec was negotiated".into()))?;
+    Ok(compress::decompress(codec, &data, header.length as usize)?)
+}
+
+/// Sync every file written since the last checkpoint, then save the journal
+async fn checkpoint(journal: &Journal, dirty: &mut HashSet<PathBuf>) -> Result<()> {
+    let files = std::mem::take(dirty);
+    let journal = journal.clone();
+    blocking(move || -> io::Result<()> {
+        for path in files {
+            File::open(&path)?.sync_data()?;
+        }
+        journal.save()
+    }).await
+}
+
+/// Where the entries of a manifest land on disk
+struct Destination {
+    root: PathBuf,
+    /// Set when a lone file is saved under a name chosen by the receiver
+    single: Option<PathBuf>,
+    /// Output path of every manifest entry, by index
+    paths: Vec<PathBuf>,
+}
+
+impl Destination {
+    fn resolve(dest: &Path, manifest: &Manifest) -> Result<Self> {
+        if Self::lone_file(manifest) && !dest.is_dir() {
+            let root = dest.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")).to_path_buf();
+            return Ok(Destination { root, single: Some(dest.to_path_buf()), paths: vec![dest.to_path_buf()] });
+        }
+        let paths = manifest
+            .entries
+            .iter()
+            .map(|e| relative_path(&e.path).map(|rel| dest.join(rel)))
+            .collect::<Result<_>>()?;
+        Ok(Destination { root: dest.to_path_buf(), single: None, paths })
+    }
+
+    /// Whether the transfer is a single regular file
+    fn lone_file(manifest: &Manifest) -> bool {
+        manifest.entries.len() == 1 && matches!(manifest.entries[0].kind, EntryKind::File { .. })
+    }
+
+    fn journal_path(&self, manifest: &Manifest) -> PathBuf {
+        match &self.single {
+            Some(path) => Journal::path_for(path),
+            None => Journal::path_in(&self.root, &manifest.digest()),
+        }
+    }
+
+    /// Create the directory tree and make sure no output would be written through a symlink
+    fn prepare(&self, manifest: &Manifest) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        for (entry, path) in manifest.entries.iter().zip(&self.paths) {
+            if self.single.is_none() {
+                refuse_symlinks(&self.root, path, entry)?;
+            }
+            match entry.kind {
+                EntryKind::Dir => fs::create_dir_all(path)?,
+                _ => {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Trim and verify every file, create symlinks, then restore metadata
+    /// (directories last and deepest first, since writing into them bumps their mtime).
+    /// Returns the symlinks this platform couldn't create.
+    fn finish(&self, manifest: &Manifest) -> Result<Vec<PathBuf>> {
+        let mut skipped = Vec::new();
+        for (entry, path) in manifest.entries.iter().zip(&self.paths) {
+            if let EntryKind::File { size, .. } = entry.kind {
+                // Drop any stale tail left over from an older, longer file at this path
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(size)?;
+                file.sync_all()?;
+                entry.verify(path)?;
+            }
+        }
+        for (entry, path) in manifest.entries.iter().zip(&self.paths) {
+            if let EntryKind::Symlink { target } = &entry.kind {
+                if !create_symlink(target, path)? {
+                    skipped.push(path.clone());
+                }
+            }
+        }
+        let mut ordered: Vec<(&Entry, &PathBuf)> = manifest.entries.iter().zip(&self.paths).collect();
+        ordered.sort_by_key(|(e, _)| (matches!(e.kind, EntryKind::Dir), std::cmp::Reverse(e.path.matches('/').count())));
+        for (entry, path) in ordered {
+            entry.apply_metadata(path)?;
+        }
+        Ok(skipped)
+    }
+}
+
+/// Fail if any existing component of `path` below `root` is a symlink. A symlink
+/// entry may replace an old link at its own path, since that one isn't followed.
+fn refuse_symlinks(root: &Path, path: &Path, entry: &Entry) -> io::Result<()> {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let mut current = root.to_path_buf();
+    let parts: Vec<_> = rel.components().collect();
+    for (i, part) in parts.iter().enumerate() {
+        current.push(part);
+        let is_link = fs::symlink_metadata(&current).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+        if is_link && i + 1 == parts.len() && matches!(entry.kind, EntryKind::Symlink { .. }) {
+            fs::remove_file(&current)?;
+        } else if is_link {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Refusing to write through existing symlink {}", current.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Create a symlink at `path`; false if this platform can't
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> io::Result<bool> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, _path: &Path) -> io::Result<bool> {
+    Ok(false)
 }
@@ -1,15 +1,42 @@
-use std::time::Instant;
+use std::time::Duration;
 use std::io::{self, Write};
 
-pub fn print_progress(transferred: usize, total: usize, start: Instant) {
+pub fn print_progress(transferred: u64, total: u64, elapsed: Duration) {
     let percent = if total > 0 {
         (transferred as f64 / total as f64) * 100.0
     } else { 0.0 };
-    let elapsed = start.elapsed().as_secs_f64().max(0.01);
+    let elapsed = elapsed.as_secs_f64().max(0.01);
     let speed = transferred as f64 / (1024.0 * 1024.0) / elapsed;
     let bar_len = 30;
     let filled = ((bar_len as f64) * transferred as f64 / (total as f64)).round() as usize;
     let bar = "█".repeat(filled) + &"-".repeat(bar_len - filled);
     print!("\r[{}] {:.1}% | {:.2} MB/s", bar, percent, speed);
-    io::stdout().flush().unwrap();
+    io::stdout().flush().ok();
+}
+
+/// Print `message` and read one trimmed line from stdin
+pub fn prompt(message: &str) -> String {
+    print!("{}", message);
+    io::stdout().flush().ok();
+    let mut line = String::new();
+    // A closed stdin reads as an empty answer
+    io::stdin().read_line(&mut line).ok();
+    line.trim().to_string()
+}
+
+/// Lowercase hex encoding of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode hex written by [`to_hex`]; `None` if it isn't exactly `N` bytes of hex
+pub fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
 }
@@ -0,0 +1,105 @@
+//! Discovery over loopback: responders answer the prober directly, share their port,
+//! and only compatible devices are listed.
+
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+use spl_rust::compress::SUPPORTED;
+use spl_rust::crypto::CIPHER;
+use spl_rust::network::{probe, start_discovery_responder, Announcement};
+use spl_rust::protocol::PROTOCOL_VERSION;
+
+const WAIT: Duration = Duration::from_millis(300);
+
+fn announcement(id: &str, name: &str) -> Announcement {
+    Announcement {
+        version: PROTOCOL_VERSION,
+        id: id.into(),
+        name: name.into(),
+        kind: "laptop".into(),
+        os: "linux".into(),
+        port: 6001,
+        ciphers: vec![CIPHER.into()],
+        compression: SUPPORTED.to_vec(),
+        fingerprint: None,
+    }
+}
+
+/// A UDP port nothing is bound to right now
+fn free_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn responder_answers_the_prober_directly() {
+    let port = free_port();
+    let laptop = announcement("a1", "laptop");
+    start_discovery_responder(port, &laptop).unwrap();
+
+    // The probe comes from an ephemeral port, so only a unicast reply can reach it
+    let devices = probe(&[SocketAddr::from(([127, 0, 0, 1], port))], "prober", WAIT).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].host, "127.0.0.1");
+    assert_eq!(devices[0].announcement, laptop);
+    assert_eq!(devices[0].target().unwrap().to_string(), "127.0.0.1:6001");
+}
+
+#[test]
+fn responder_answers_over_ipv6() {
+    if UdpSocket::bind("[::1]:0").is_err() {
+        return; // no IPv6 on this host
+    }
+    let port = free_port();
+    start_discovery_responder(port, &announcement("b1", "desktop")).unwrap();
+
+    let devices = probe(&[SocketAddr::from((Ipv6Addr::LOCALHOST, port))], "prober", WAIT).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].host, "::1");
+    assert_eq!(devices[0].target().unwrap().to_string(), "[::1]:6001");
+}
+
+#[test]
+fn responders_share_the_discovery_port() {
+    let port = free_port();
+    start_discovery_responder(port, &announcement("c1", "cli")).unwrap();
+    start_discovery_responder(port, &announcement("c2", "bridge")).unwrap();
+
+    // A unicast probe reaches one of them; broadcasts would reach both
+    let devices = probe(&[SocketAddr::from(([127, 0, 0, 1], port))], "prober", WAIT).unwrap();
+    assert_eq!(devices.len(), 1);
+    assert!(["c1", "c2"].contains(&devices[0].announcement.id.as_str()));
+}
+
+#[test]
+fn incompatible_devices_are_left_out() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let mut future = announcement("d1", "future");
+    future.version = PROTOCOL_VERSION + 1;
+    let mut other_cipher = announcement("d2", "other");
+    other_cipher.ciphers = vec!["rot13".into()];
+    thread::spawn(move || {
+        let mut buffer = [0u8; 64];
+        let (_, prober) = socket.recv_from(&mut buffer).unwrap();
+        socket.send_to(b"SPL_HERE", prober).unwrap();
+        socket.send_to(&future.encode(), prober).unwrap();
+        socket.send_to(&other_cipher.encode(), prober).unwrap();
+    });
+
+    let devices = probe(&[SocketAddr::from(([127, 0, 0, 1], port))], "prober", WAIT).unwrap();
+    assert!(devices.is_empty(), "listed {:?}", devices);
+}
+
+#[test]
+fn prober_leaves_out_its_own_responder() {
+    let own_port = free_port();
+    let other_port = free_port();
+    start_discovery_responder(own_port, &announcement("e1", "this")).unwrap();
+    start_discovery_responder(other_port, &announcement("e2", "that")).unwrap();
+
+    let targets = [SocketAddr::from(([127, 0, 0, 1], own_port)), SocketAddr::from(([127, 0, 0, 1], other_port))];
+    let devices = probe(&targets, "e1", WAIT).unwrap();
+    assert_eq!(devices.len(), 1, "listed {:?}", devices);
+    assert_eq!(devices[0].announcement.id, "e2");
+}
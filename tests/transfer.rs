@@ -0,0 +1,188 @@
+//! Transfers over loopback: a tree spread over several connections arrives intact, and a
+//! transfer cut off partway resumes where it stopped.
+
+use std::fs;
+use std::net::{SocketAddr, TcpListener as StdListener};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use spl_rust::{Result, SessionBuilder, TransferEvent, TransferReport};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A fresh, empty scratch directory for `test`
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spl-transfer-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// `len` bytes that don't compress, different for every `seed`
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// A TCP port nothing is listening on right now
+fn free_port() -> u16 {
+    StdListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Every file under `root`, relative to it, with its contents
+fn files(root: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut found = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).unwrap().map(std::io::Result::unwrap) {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if !entry.file_name().to_string_lossy().starts_with(".spl-journal") {
+                found.push((path.strip_prefix(root).unwrap().to_path_buf(), fs::read(&path).unwrap()));
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Receive one transfer into `dest` on `port`, reporting every event on the returned channel
+/// once the receiver is listening
+async fn start_receiver(
+    port: u16,
+    dest: PathBuf,
+    streams: u8,
+) -> (JoinHandle<Result<TransferReport>>, mpsc::UnboundedReceiver<TransferEvent>) {
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let receiver = SessionBuilder::new()
+        .port(port)
+        .streams(streams)
+        .on_event(move |event| {
+            let _ = events_tx.send(event);
+        })
+        .receiver();
+    let task = tokio::spawn(async move { receiver.receive(dest).await });
+    match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+        Ok(Some(TransferEvent::Listening { .. })) => {}
+        other => panic!("expected to be listening, got {:?}", other),
+    }
+    (task, events)
+}
+
+#[tokio::test]
+async fn tree_arrives_intact_over_several_streams() {
+    let dir = scratch("streams");
+    let source = dir.join("tree");
+    fs::create_dir_all(source.join("nested/deeper")).unwrap();
+    fs::write(source.join("big.bin"), noise(40 * CHUNK_SIZE + 123, 1)).unwrap();
+    fs::write(source.join("nested/medium.bin"), noise(7 * CHUNK_SIZE, 2)).unwrap();
+    fs::write(source.join("nested/deeper/small.txt"), b"hello").unwrap();
+    fs::write(source.join("empty"), b"").unwrap();
+    let dest = dir.join("received");
+    fs::create_dir_all(&dest).unwrap();
+
+    let port = free_port();
+    let (receiving, mut events) = start_receiver(port, dest.clone(), 4).await;
+    let sender = SessionBuilder::new().chunk_size(CHUNK_SIZE).streams(4).sender();
+    let sent = sender.send(&[&source], &format!("127.0.0.1:{}", port)).await.unwrap();
+    let received = receiving.await.unwrap().unwrap();
+
+    assert_eq!(sent.attempts, 1);
+    assert_eq!(received.manifest, sent.manifest);
+    let streams: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+        .filter_map(|event| match event {
+            TransferEvent::Started { streams, .. } => Some(streams),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(streams, [4]);
+    assert_eq!(files(&dest.join("tree")), files(&source));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Forward connections on `port` to `target`. Once `cut_after` bytes have gone towards the
+/// target, every connection open at that moment is dropped; later ones pass untouched.
+async fn start_flaky_proxy(port: u16, target: SocketAddr, cut_after: usize) {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    let forwarded = Arc::new(AtomicUsize::new(0));
+    let cut_once = Arc::new(AtomicBool::new(false));
+    let (cut, _) = watch::channel(());
+    let cut = Arc::new(cut);
+    tokio::spawn(async move {
+        while let Ok((client, _)) = listener.accept().await {
+            let server = TcpStream::connect(target).await.unwrap();
+            let (forwarded, cut_once, cut) = (forwarded.clone(), cut_once.clone(), cut.clone());
+            let mut dropped = cut.subscribe();
+            tokio::spawn(async move {
+                let (mut from_client, mut to_client) = client.into_split();
+                let (mut from_server, mut to_server) = server.into_split();
+                let upstream = async {
+                    let mut buffer = vec![0u8; 16 * 1024];
+                    loop {
+                        let n = from_client.read(&mut buffer).await?;
+                        if n == 0 {
+                            return to_server.shutdown().await;
+                        }
+                        to_server.write_all(&buffer[..n]).await?;
+                        if forwarded.fetch_add(n, Ordering::SeqCst) + n > cut_after && !cut_once.swap(true, Ordering::SeqCst) {
+                            cut.send_replace(());
+                        }
+                    }
+                };
+                let downstream = tokio::io::copy(&mut from_server, &mut to_client);
+                tokio::select! {
+                    _ = async { tokio::join!(upstream, downstream) } => {}
+                    _ = dropped.changed() => {}
+                }
+            });
+        }
+    });
+}
+
+#[tokio::test]
+async fn interrupted_transfer_resumes() {
+    let dir = scratch("resume");
+    let source = dir.join("tree");
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("a.bin"), noise(48 * CHUNK_SIZE, 3)).unwrap();
+    fs::write(source.join("b.bin"), noise(16 * CHUNK_SIZE + 1, 4)).unwrap();
+    let dest = dir.join("received");
+    fs::create_dir_all(&dest).unwrap();
+
+    let port = free_port();
+    let (receiving, mut events) = start_receiver(port, dest.clone(), 2).await;
+    let proxy_port = free_port();
+    start_flaky_proxy(proxy_port, SocketAddr::from(([127, 0, 0, 1], port)), 24 * CHUNK_SIZE).await;
+
+    let sender = SessionBuilder::new().chunk_size(CHUNK_SIZE).streams(2).sender();
+    let sent = sender.send(&[&source], &format!("127.0.0.1:{}", proxy_port)).await.unwrap();
+    let received = receiving.await.unwrap().unwrap();
+
+    assert!(sent.attempts > 1, "the connection was never cut");
+    assert!(received.attempts > 1);
+    let resumed: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+        .filter_map(|event| match event {
+            TransferEvent::Started { resumed_chunks, .. } => Some(resumed_chunks),
+            _ => None,
+        })
+        .collect();
+    assert!(resumed.len() > 1 && resumed[0] == 0, "started {:?}", resumed);
+    assert!(*resumed.last().unwrap() > 0, "nothing was resumed: {:?}", resumed);
+    assert_eq!(files(&dest.join("tree")), files(&source));
+    fs::remove_dir_all(&dir).unwrap();
+}